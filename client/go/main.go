@@ -9,6 +9,7 @@ package main
 
 import (
 	"context"
+	"encoding/json"
 	"flag"
 	"fmt"
 	"log"
@@ -24,6 +25,13 @@ var addr = flag.String("addr", "localhost:8888", "http service address")
 
 var wg = sync.WaitGroup{}
 
+// The envelope every broadcast comes in, e.g.
+// {"op":"message","channel":"global","seq":3,"data":"42",...}
+type envelope struct {
+	Op   string `json:"op"`
+	Data string `json:"data"`
+}
+
 func main() {
 	ctx, cancel := context.WithCancel(context.Background())
 
@@ -91,7 +99,17 @@ func startChatter(id int, workerCount int, resultChan chan int, ctx context.Cont
 			}
 			// log.Printf("recv: %s, id: %v", message, id)
 
-			nr, err := strconv.Atoi(string(message))
+			var msg envelope
+			if err := json.Unmarshal(message, &msg); err != nil {
+				log.Println("parse:", err)
+				return
+			}
+			// Not a broadcast, e.g. the session event every connection starts with
+			if msg.Op != "message" {
+				continue
+			}
+
+			nr, err := strconv.Atoi(msg.Data)
 			if err != nil {
 				log.Println("parse:", err)
 				return
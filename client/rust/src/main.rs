@@ -1,50 +1,206 @@
 use core::time;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
 
+use serde_json::Value;
 use tungstenite::{connect, Message};
 use url::Url;
 
+/// What the workers saw so far
+#[derive(Default)]
+struct Stats {
+    connected: AtomicI32,
+    /// Workers that couldn't connect
+    failed: AtomicI32,
+    received: AtomicU64,
+    latest: AtomicI32,
+    /// Frames of the wrong kind, or binary frames that didn't arrive intact
+    corrupt: AtomicU64,
+    /// Broadcasts a worker never got, going by the sequence numbers
+    gaps: AtomicU64,
+    /// Broadcasts a worker got twice or after a later one
+    reordered: AtomicU64,
+}
+
+/// Whether the number is passed around in text or binary frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Text,
+    Binary,
+}
+
+/// What a frame turned out to be
+enum Decoded {
+    /// The number and, for text frames, the sequence number of the broadcast
+    Broadcast(i32, Option<u64>),
+    /// An event that isn't a broadcast, e.g. the session event every connection gets first
+    Event,
+    /// Not what we sent
+    Corrupt,
+}
+
+/// Bytes after the number in a binary frame, derived from the number so every
+/// frame can be checked on arrival
+const BINARY_PADDING: usize = 60;
+
+impl Mode {
+    fn encode(self, nr: i32) -> Message {
+        match self {
+            Mode::Text => Message::Text(format!("{}", nr)),
+            Mode::Binary => {
+                let mut data = nr.to_be_bytes().to_vec();
+                data.extend((0..BINARY_PADDING).map(|i| (nr as u8).wrapping_add(i as u8)));
+                Message::Binary(data)
+            }
+        }
+    }
+
+    /// Text frames are events, the number is in the envelope of a broadcast
+    fn decode(self, msg: &Message) -> Decoded {
+        let envelope = match msg {
+            Message::Text(msg) => serde_json::from_str::<Value>(msg).ok(),
+            _ => None,
+        };
+        if let Some(envelope) = &envelope {
+            if envelope["op"].is_string() && envelope["op"] != "message" {
+                return Decoded::Event;
+            }
+        }
+
+        let broadcast = match (self, msg, envelope) {
+            (Mode::Text, _, Some(envelope)) => envelope["data"]
+                .as_str()
+                .and_then(|data| data.parse().ok())
+                .zip(envelope["seq"].as_u64())
+                .map(|(nr, seq)| (nr, Some(seq))),
+            (Mode::Binary, Message::Binary(data), _) => data
+                .get(..4)
+                .and_then(|nr| nr.try_into().ok())
+                .map(i32::from_be_bytes)
+                .filter(|nr| self.encode(*nr) == *msg)
+                .map(|nr| (nr, None)),
+            _ => None,
+        };
+        match broadcast {
+            Some((nr, seq)) => Decoded::Broadcast(nr, seq),
+            None => Decoded::Corrupt,
+        }
+    }
+}
+
+/// Usage: `ws-client [workers] [seconds] [text|binary]`
+///
+/// Every worker subscribes to `/global`. A single message is passed around, the worker
+/// whose id matches the number in it replies with the next number, so every round
+/// is one broadcast to all workers. Prints the broadcast and delivery rate at the end.
+/// In text mode every worker checks the sequence numbers of the envelopes it gets for
+/// gaps and reordering. In binary mode the number goes out in binary frames, which carry
+/// no envelope but are verified on arrival.
 fn main() {
-    let worker_count: i32 = 1000;
+    let mut args = std::env::args().skip(1);
+    let worker_count: i32 = args
+        .next()
+        .map_or(1000, |arg| arg.parse().expect("Invalid worker count"));
+    let seconds: u64 = args
+        .next()
+        .map_or(5, |arg| arg.parse().expect("Invalid duration"));
+    let mode = match args.next().as_deref() {
+        None | Some("text") => Mode::Text,
+        Some("binary") => Mode::Binary,
+        Some(mode) => panic!("Invalid mode {mode}, expected text or binary"),
+    };
 
-    let mut handlers = Vec::new();
+    let stats = Arc::new(Stats::default());
     for id in 0..worker_count {
-        let join_handel = thread::spawn(move || start_client(id, worker_count));
-
-        handlers.push(join_handel);
+        let stats = stats.clone();
+        thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(move || start_client(id, worker_count, mode, stats))
+            .expect("Can't spawn worker");
     }
 
+    // Wait for every worker to connect or give up
+    while stats.connected.load(Ordering::Relaxed) + stats.failed.load(Ordering::Relaxed)
+        < worker_count
+    {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    let connected = stats.connected.load(Ordering::Relaxed);
+    if connected < worker_count {
+        eprintln!("Only {connected} of {worker_count} workers connected");
+        std::process::exit(1);
+    }
+    // Give the server a moment to seat the last workers in the channel
     thread::sleep(time::Duration::from_secs(1));
 
     let (mut socket, _response) =
         connect(Url::parse("ws://localhost:8888/global").unwrap()).expect("Can't connect");
 
-    socket.send(Message::Text("1".into())).unwrap();
+    let start = Instant::now();
+    socket.send(mode.encode(1)).unwrap();
 
-    for handel in handlers {
-        handel.join().unwrap();
-    }
+    thread::sleep(time::Duration::from_secs(seconds));
+    let elapsed = start.elapsed().as_secs_f64();
+    let received = stats.received.load(Ordering::Relaxed);
+    let latest = stats.latest.load(Ordering::Relaxed);
+    let corrupt = stats.corrupt.load(Ordering::Relaxed);
+    let gaps = stats.gaps.load(Ordering::Relaxed);
+    let reordered = stats.reordered.load(Ordering::Relaxed);
+
+    // The workers are blocked on their sockets, they go away with the process
+    println!("Workers: {worker_count}, seconds: {elapsed:.1}, mode: {mode:?}");
+    println!("Broadcasts: {latest} ({:.0}/s)", latest as f64 / elapsed);
+    println!(
+        "Deliveries: {received} ({:.0}/s)",
+        received as f64 / elapsed
+    );
+    println!("Corrupt: {corrupt}, gaps: {gaps}, reordered: {reordered}");
 }
 
-fn start_client(id: i32, worker_count: i32) {
-    let (mut socket, _response) =
-        connect(Url::parse("ws://localhost:8888/global").unwrap()).expect("Can't connect");
+fn start_client(id: i32, worker_count: i32, mode: Mode, stats: Arc<Stats>) {
+    let mut socket = match connect(Url::parse("ws://localhost:8888/global").unwrap()) {
+        Ok((socket, _response)) => socket,
+        Err(err) => {
+            eprintln!("Worker {id} can't connect: {err}");
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    stats.connected.fetch_add(1, Ordering::Relaxed);
+    // Every worker is in the channel before the first broadcast, so they all start at 1
+    let mut last_seq = 0;
 
     loop {
         let msg = socket.read().expect("Error reading message");
-        println!("ID: {}, Received: {}", id, msg);
         match msg {
-            Message::Text(msg) => {
-                let nr: i32 = msg.parse().unwrap();
+            Message::Text(_) | Message::Binary(_) => {
+                let decoded = mode.decode(&msg);
+                if let Decoded::Event = decoded {
+                    continue;
+                }
+                stats.received.fetch_add(1, Ordering::Relaxed);
+                let Decoded::Broadcast(nr, seq) = decoded else {
+                    stats.corrupt.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                if let Some(seq) = seq {
+                    if seq <= last_seq {
+                        stats.reordered.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        stats.gaps.fetch_add(seq - last_seq - 1, Ordering::Relaxed);
+                        last_seq = seq;
+                    }
+                }
+                stats.latest.fetch_max(nr, Ordering::Relaxed);
                 if nr % worker_count == id {
-                    socket.send(Message::Text(format!("{}", nr + 1))).unwrap();
+                    socket.send(mode.encode(nr + 1)).unwrap();
                 }
             }
-            Message::Binary(_) => todo!(),
-            Message::Ping(_) => todo!(),
-            Message::Pong(_) => todo!(),
-            Message::Close(_) => todo!(),
-            Message::Frame(_) => todo!(),
+            // tungstenite answers pings by itself
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            Message::Close(_) => return,
         }
     }
     // socket.close(None);
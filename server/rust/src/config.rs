@@ -0,0 +1,219 @@
+//! The config file given with `--config`, shared by every backend.
+//!
+//! Every section and setting is optional, e.g.
+//!
+//! ```toml
+//! [balancer]
+//! selector = "power-of-two-choices"
+//! layers = "5,50"
+//! autoscale = "200,20,1000"
+//!
+//! [channel]
+//! shards = 4
+//!
+//! [broadcast]
+//! capacity = 500
+//!
+//! [history]
+//! capacity = 100
+//!
+//! [history.journal]
+//! dir = "/var/lib/ws-server/journal"
+//! fsync = "every:100"
+//! segment_bytes = 16777216
+//! retention_bytes = 1073741824
+//! retention_secs = 604800
+//!
+//! [outbox]
+//! capacity = 500
+//! policy = "disconnect:100"
+//!
+//! [heartbeat]
+//! interval_secs = 30
+//! max_missed = 2
+//!
+//! [resume]
+//! grace_secs = 30
+//!
+//! [ack]
+//! window = 100
+//! timeout_ms = 5000
+//!
+//! [shutdown]
+//! drain_timeout_secs = 10
+//! ```
+//!
+//! The environment variables the settings used to be read from still override the file.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::history;
+use crate::outbox;
+use crate::ractor;
+use crate::server::{ack, heartbeat, resume, shutdown};
+use crate::tokio_actors;
+use crate::tokio_broadcast;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Only used by the ractor backend
+    pub balancer: ractor::Config,
+    /// Only used by the tokio-actors backend
+    pub channel: tokio_actors::Config,
+    /// Only used by the tokio-broadcast backend
+    pub broadcast: tokio_broadcast::Config,
+    pub history: history::Config,
+    pub outbox: outbox::Config,
+    pub heartbeat: heartbeat::Config,
+    pub resume: resume::Config,
+    pub ack: ack::Config,
+    pub shutdown: shutdown::Config,
+}
+
+impl Config {
+    /// Reads the file, or starts from the defaults without one, then applies the
+    /// environment variables
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let mut config = match path {
+            Some(path) => {
+                let file = std::fs::read_to_string(path)
+                    .map_err(|err| format!("can't read {}: {err}", path.display()))?;
+                toml::from_str(&file).map_err(|err| format!("invalid {}: {err}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        config.balancer.apply_env()?;
+        config.channel.apply_env()?;
+        config.broadcast.apply_env()?;
+        config.history.apply_env()?;
+        config.outbox.apply_env()?;
+        config.heartbeat.apply_env()?;
+        config.resume.apply_env()?;
+        config.ack.apply_env()?;
+        config.shutdown.apply_env()?;
+
+        Ok(config)
+    }
+}
+
+/// Deserializes a setting from the same string its environment variable takes
+pub fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+}
+
+/// Like [parse] for a setting that is off unless given
+pub fn parse_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    parse(deserializer).map(Some)
+}
+
+/// Reads an environment variable, [None] when it isn't set
+pub fn env<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| format!("invalid {name}: {err}")),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal;
+
+    #[test]
+    fn parses_every_section() {
+        let config: Config = toml::from_str(
+            r#"
+            [balancer]
+            selector = "round-robin"
+            layers = "none"
+            autoscale = "200,20"
+
+            [channel]
+            shards = 4
+
+            [broadcast]
+            capacity = 50
+
+            [history]
+            capacity = 0
+
+            [history.journal]
+            dir = "journal"
+            fsync = "always"
+
+            [outbox]
+            capacity = 10
+            policy = "disconnect:5"
+
+            [heartbeat]
+            interval_secs = 0
+
+            [resume]
+            grace_secs = 5
+
+            [ack]
+            window = 10
+
+            [shutdown]
+            drain_timeout_secs = 3
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.balancer.topology.layers.is_empty());
+        assert!(config.balancer.autoscale.is_some());
+        assert_eq!(config.channel.shards.get(), 4);
+        assert_eq!(config.broadcast.capacity, 50);
+        assert_eq!(config.history.capacity, 0);
+        assert_eq!(config.history.journal.dir, Some("journal".into()));
+        assert_eq!(config.history.journal.fsync, journal::Fsync::Always);
+        assert_eq!(config.outbox.capacity, 10);
+        assert_eq!(config.outbox.policy, outbox::Policy::Disconnect(5));
+        assert_eq!(config.heartbeat.interval_secs, 0);
+        assert_eq!(config.heartbeat.max_missed.get(), 2);
+        assert_eq!(config.resume.grace_secs, 5);
+        assert_eq!(config.ack.window.get(), 10);
+        assert_eq!(config.ack.timeout_ms, 5000);
+        assert_eq!(config.shutdown.drain_timeout_secs, 3);
+    }
+
+    #[test]
+    fn missing_settings_keep_their_defaults() {
+        let config: Config = toml::from_str("[outbox]\ncapacity = 10").unwrap();
+
+        assert_eq!(config.outbox.policy, outbox::Config::default().policy);
+        assert_eq!(config.balancer.topology, Default::default());
+        assert!(config.balancer.autoscale.is_none());
+        assert!(config.history.journal.dir.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(toml::from_str::<Config>("[outbox]\npolicy = \"drop\"").is_err());
+        assert!(toml::from_str::<Config>("[balancer]\nlayer = \"5\"").is_err());
+        assert!(toml::from_str::<Config>("[channel]\nshards = 0").is_err());
+        assert!(toml::from_str::<Config>("[ack]\nwindow = 0").is_err());
+        assert!(toml::from_str::<Config>("[heartbeat]\nmax_missed = 0").is_err());
+        assert!(toml::from_str::<Config>("[history.journal]\nfsync = \"every:0\"").is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Errors shared by every backend, and what each of them means for the connection
+//! or subscriber that ran into it.
+
+use std::fmt;
+use std::io;
+
+use axum::extract::ws::close_code;
+use tokio::sync::mpsc;
+
+use crate::server::session::ConnectionId;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The actor on the other end stopped, e.g. a connection whose socket went away
+    ActorGone,
+    /// The actor on the other end is alive but its mailbox is full
+    MailboxFull,
+    /// Writing to the websocket failed, usually because the peer reset the connection
+    Socket(axum::Error),
+    /// The client reads too slowly and fell too far behind, see [crate::outbox::Policy]
+    SlowConsumer,
+    /// Spawning an actor failed
+    Spawn(String),
+    /// The named channel could not be started, or the registry didn't answer
+    ChannelUnavailable(String),
+    /// The named channel kept failing and was given up on
+    ChannelFailed(String),
+    /// The client didn't answer this many pings in a row, see [crate::server::heartbeat]
+    MissedHeartbeats(u32),
+    /// The server is shutting down, see [crate::server::shutdown]
+    ShuttingDown,
+    /// A direct message went to a connection that isn't there, or is gone by now
+    UnknownConnection(ConnectionId),
+    /// The journal of the named channel couldn't be read, see [crate::journal]
+    Journal(String, io::Error),
+    /// A message to the named channel couldn't be written to its journal, so it wasn't
+    /// sent out either
+    Unjournaled(String, io::Error),
+    /// The client left this many messages unacknowledged, see [crate::server::ack]
+    Unacked(usize),
+    /// The connection is closing and its outbox takes no more frames, see
+    /// [crate::outbox::Outbox::close]
+    Closed,
+    /// The client sent a JSON object with an `op` that isn't a valid command, see
+    /// [crate::protocol::Command::parse]
+    BadCommand(String),
+}
+
+/// How a failure is dealt with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Stop delivering to the subscriber, it is not coming back
+    DropSubscriber,
+    /// Skip this message, the subscriber is still there
+    DropMessage,
+    /// Try the operation again
+    Retry,
+    /// Close the websocket with this close code
+    Close(u16),
+    /// Tell the client in an [crate::protocol::Event::Error], the connection stays open
+    Report,
+}
+
+impl Error {
+    pub fn recovery(&self) -> Recovery {
+        match self {
+            Error::ActorGone | Error::Closed => Recovery::DropSubscriber,
+            Error::MailboxFull => Recovery::DropMessage,
+            Error::Spawn(_) => Recovery::Retry,
+            Error::Socket(_) | Error::ChannelFailed(_) | Error::Journal(..) => {
+                Recovery::Close(close_code::ERROR)
+            }
+            Error::ChannelUnavailable(_) => Recovery::Close(close_code::AGAIN),
+            Error::SlowConsumer | Error::MissedHeartbeats(_) | Error::Unacked(_) => {
+                Recovery::Close(close_code::POLICY)
+            }
+            Error::ShuttingDown => Recovery::Close(close_code::AWAY),
+            Error::UnknownConnection(_) | Error::BadCommand(_) | Error::Unjournaled(..) => {
+                Recovery::Report
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ActorGone => write!(f, "actor is gone"),
+            Error::MailboxFull => write!(f, "mailbox is full"),
+            Error::Socket(err) => write!(f, "websocket error: {err}"),
+            Error::SlowConsumer => write!(f, "client fell too far behind"),
+            Error::Spawn(err) => write!(f, "failed to spawn actor: {err}"),
+            Error::ChannelUnavailable(name) => write!(f, "channel {name} is unavailable"),
+            Error::ChannelFailed(name) => write!(f, "channel {name} failed"),
+            Error::MissedHeartbeats(missed) => write!(f, "client missed {missed} heartbeats"),
+            Error::ShuttingDown => write!(f, "server is shutting down"),
+            Error::UnknownConnection(id) => write!(f, "connection {id} is not connected"),
+            Error::Journal(name, err) => write!(f, "journal of channel {name} failed: {err}"),
+            Error::Unjournaled(name, err) => {
+                write!(f, "message to channel {name} was not journaled: {err}")
+            }
+            Error::Unacked(count) => write!(f, "client left {count} messages unacknowledged"),
+            Error::Closed => write!(f, "connection is closing"),
+            Error::BadCommand(err) => write!(f, "invalid command: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<axum::Error> for Error {
+    fn from(err: axum::Error) -> Self {
+        Error::Socket(err)
+    }
+}
+
+impl<T> From<mpsc::error::TrySendError<T>> for Error {
+    fn from(err: mpsc::error::TrySendError<T>) -> Self {
+        match err {
+            mpsc::error::TrySendError::Full(_) => Error::MailboxFull,
+            mpsc::error::TrySendError::Closed(_) => Error::ActorGone,
+        }
+    }
+}
+
+impl<T> From<mpsc::error::SendError<T>> for Error {
+    fn from(_: mpsc::error::SendError<T>) -> Self {
+        Error::ActorGone
+    }
+}
+
+// ractor mailboxes are unbounded, a send only fails once the actor stopped
+impl<T> From<ractor::MessagingErr<T>> for Error {
+    fn from(_: ractor::MessagingErr<T>) -> Self {
+        Error::ActorGone
+    }
+}
+
+impl From<ractor::SpawnErr> for Error {
+    fn from(err: ractor::SpawnErr) -> Self {
+        Error::Spawn(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mailbox_errors() {
+        let (sender, receiver) = mpsc::channel(1);
+        sender.try_send(()).unwrap();
+
+        let err = Error::from(sender.try_send(()).unwrap_err());
+        assert_eq!(err.recovery(), Recovery::DropMessage);
+
+        drop(receiver);
+        let err = Error::from(sender.try_send(()).unwrap_err());
+        assert_eq!(err.recovery(), Recovery::DropSubscriber);
+    }
+
+    #[test]
+    fn connection_closing_errors() {
+        assert_eq!(
+            Error::ChannelUnavailable("x".into()).recovery(),
+            Recovery::Close(close_code::AGAIN)
+        );
+        assert_eq!(
+            Error::ChannelFailed("x".into()).recovery(),
+            Recovery::Close(close_code::ERROR)
+        );
+        assert_eq!(
+            Error::Socket(axum::Error::new("reset")).recovery(),
+            Recovery::Close(close_code::ERROR)
+        );
+        assert_eq!(
+            Error::SlowConsumer.recovery(),
+            Recovery::Close(close_code::POLICY)
+        );
+        assert_eq!(
+            Error::MissedHeartbeats(2).recovery(),
+            Recovery::Close(close_code::POLICY)
+        );
+        assert_eq!(
+            Error::Unacked(100).recovery(),
+            Recovery::Close(close_code::POLICY)
+        );
+        assert_eq!(
+            Error::ShuttingDown.recovery(),
+            Recovery::Close(close_code::AWAY)
+        );
+    }
+
+    #[test]
+    fn unknown_connections_are_reported() {
+        assert_eq!(Error::UnknownConnection(7).recovery(), Recovery::Report);
+    }
+
+    #[test]
+    fn spawn_errors_are_retried() {
+        let err = Error::from(ractor::SpawnErr::StartupCancelled);
+        assert_eq!(err.recovery(), Recovery::Retry);
+    }
+}
@@ -0,0 +1,358 @@
+//! The last broadcasts of every channel, shared by every backend.
+//!
+//! A connection joining a channel can catch up on what it missed before getting live
+//! broadcasts, with `{"op":"subscribe","channel":"x","history":10}` for the last 10
+//! messages or `{"op":"subscribe","channel":"x","since":42}` for everything after
+//! sequence number 42. The channel from the url takes `?history=10` or `?since=42`.
+//! The backend takes the replay at the very point the connection starts getting live
+//! broadcasts, so none is missed or delivered twice in between. A channel only keeps its
+//! last [Config::capacity] broadcasts, a client asking for more sees the gap in `seq`,
+//! and forgets them along with the channel once its last connection left, unless they
+//! are kept in a [crate::journal] as well.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+use crate::config;
+use crate::error::Error;
+use crate::journal::{self, Writer};
+use crate::protocol::{Broadcast, Publication};
+
+/// The `[history]` section of the config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How many broadcasts every channel keeps for replay, 0 turns it off
+    pub capacity: usize,
+    pub journal: journal::Config,
+}
+
+impl Config {
+    /// Overrides the file with `HISTORY_CAPACITY` and the journal variables
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(capacity) = config::env("HISTORY_CAPACITY")? {
+            self.capacity = capacity;
+        }
+
+        self.journal.apply_env()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            journal: journal::Config::default(),
+        }
+    }
+}
+
+/// Epochs of histories without a journal, 0 is for the ones with a journal. Every
+/// history takes one, see [History::release].
+static NEXT_EPOCH: AtomicU64 = AtomicU64::new(1);
+
+/// How far a connection got in a channel, see [crate::server::resume]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    /// See [History::epoch]
+    pub epoch: u64,
+    /// The sequence number of the last broadcast the connection got
+    pub seq: u64,
+}
+
+/// What a joining connection wants replayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replay {
+    /// The last so many broadcasts
+    Last(usize),
+    /// Every broadcast with a higher sequence number
+    Since(u64),
+    /// Every broadcast after the cursor, or every one kept when the channel started its
+    /// sequence numbers over since
+    Resume(Cursor),
+}
+
+impl Replay {
+    /// From the `history` and `since` a client gave, `since` wins when it gave both
+    pub fn new(history: Option<usize>, since: Option<u64>) -> Option<Self> {
+        since.map(Replay::Since).or(history.map(Replay::Last))
+    }
+}
+
+/// What a connection joining a channel got replayed
+#[derive(Debug, Default)]
+pub struct Replayed {
+    /// See [History::epoch]
+    pub epoch: u64,
+    /// The sequence number of the last broadcast when the replay was taken, whatever
+    /// the connection gets live is either newer or replayed already
+    pub seq: u64,
+    pub broadcasts: Vec<Arc<Broadcast>>,
+}
+
+/// The last broadcasts of one channel, oldest first. It stamps the channel's
+/// broadcasts as well, and holds each one back until it and every one before it were
+/// written to the journal.
+#[derive(Debug)]
+pub struct History {
+    capacity: usize,
+    broadcasts: VecDeque<Arc<Broadcast>>,
+    journal: Option<Writer>,
+    epoch: u64,
+    /// Tells this history apart from the ones the channel had before, see [Appended]
+    run: u64,
+    /// The sequence number of the last broadcast stamped
+    stamped: u64,
+    /// The sequence number of the last broadcast released, every one up to it is kept
+    seq: u64,
+    /// Broadcasts written while one before them still isn't, [None] for one that
+    /// couldn't be written
+    held: BTreeMap<u64, Option<Arc<Broadcast>>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        let run = NEXT_EPOCH.fetch_add(1, Ordering::Relaxed);
+        Self {
+            capacity,
+            broadcasts: VecDeque::with_capacity(capacity),
+            journal: None,
+            epoch: run,
+            run,
+            stamped: 0,
+            seq: 0,
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Tells apart the runs of a channel, a channel stopped with its last connection
+    /// starts its sequence numbers over unless it keeps a journal
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The sequence number of the last broadcast released, whatever the channel fans out
+    /// afterwards is newer
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The history of a starting channel, filled from its journal when there is one and
+    /// carrying on after the last broadcast in it
+    pub async fn open(config: &Config, channel: &str) -> Result<Self, Error> {
+        let mut history = Self::new(config.capacity);
+        let Some((journal, seq, tail)) = Writer::open(&config.journal, channel, config.capacity)
+            .await
+            .map_err(|err| Error::Journal(channel.to_string(), err))?
+        else {
+            return Ok(history);
+        };
+
+        history.broadcasts.extend(tail);
+        history.journal = Some(journal);
+        history.epoch = 0;
+        history.stamped = seq;
+        history.seq = seq;
+
+        Ok(history)
+    }
+
+    /// Stamp the next sequence number on a publication and hand it to the journal. The
+    /// broadcast goes out once [History::release] gets it back written.
+    pub fn append(&mut self, channel: &str, msg: Publication) -> Appending {
+        self.stamped += 1;
+        let msg = Broadcast::new(channel, Some(self.stamped), msg);
+        Appending {
+            run: self.run,
+            written: self.journal.as_ref().map(|journal| journal.append(&msg)),
+            msg,
+        }
+    }
+
+    /// Keep a broadcast that was written, or skip one that couldn't be. Returns the
+    /// broadcasts to fan out now, oldest first, along with the ones before it that were
+    /// held back for it. Broadcasts of an earlier run of the channel are ignored.
+    pub fn release(&mut self, appended: &Appended) -> Vec<Arc<Broadcast>> {
+        let seq = appended.msg.seq().unwrap_or_default();
+        if appended.run != self.run || seq <= self.seq {
+            return Vec::new();
+        }
+        let msg = appended.result.is_ok().then(|| appended.msg.clone());
+        self.held.insert(seq, msg);
+
+        let mut released = Vec::new();
+        while let Some(msg) = self.held.remove(&(self.seq + 1)) {
+            self.seq += 1;
+            if let Some(msg) = msg {
+                self.push(&msg);
+                released.push(msg);
+            }
+        }
+        released
+    }
+
+    /// Keep a broadcast of the channel, dropping the oldest one when full
+    fn push(&mut self, msg: &Arc<Broadcast>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.broadcasts.len() == self.capacity {
+            self.broadcasts.pop_front();
+        }
+        self.broadcasts.push_back(msg.clone());
+    }
+
+    pub fn replay(&self, replay: Replay) -> Vec<Arc<Broadcast>> {
+        match replay {
+            Replay::Last(count) => {
+                let skip = self.broadcasts.len().saturating_sub(count);
+                self.broadcasts.iter().skip(skip).cloned().collect()
+            }
+            Replay::Since(seq) => self
+                .broadcasts
+                .iter()
+                .skip_while(|msg| msg.seq().is_some_and(|msg_seq| msg_seq <= seq))
+                .cloned()
+                .collect(),
+            Replay::Resume(cursor) if cursor.epoch == self.epoch => {
+                self.replay(Replay::Since(cursor.seq))
+            }
+            Replay::Resume(_) => self.replay(Replay::Since(0)),
+        }
+    }
+}
+
+/// A broadcast on its way into the journal, see [History::append]
+#[derive(Debug)]
+pub struct Appending {
+    run: u64,
+    msg: Arc<Broadcast>,
+    /// [None] without a journal, the broadcast counts as written right away
+    written: Option<oneshot::Receiver<io::Result<()>>>,
+}
+
+impl Appending {
+    /// Wait for the journal to write the broadcast
+    pub async fn written(self) -> Appended {
+        let result = match self.written {
+            Some(written) => written
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("the journal writer stopped"))),
+            None => Ok(()),
+        };
+        Appended::new(self.run, self.msg, result)
+    }
+
+    /// The broadcast as [Appending::written] has it when there is no journal to wait
+    /// for. The journal may well be done with it already, but whatever it released in the
+    /// meantime goes out first.
+    pub fn try_written(self) -> Result<Appended, Self> {
+        match self.written {
+            Some(_) => Err(self),
+            None => Ok(Appended::new(self.run, self.msg, Ok(()))),
+        }
+    }
+}
+
+/// A broadcast the journal is done with, for [History::release]
+#[derive(Debug)]
+pub struct Appended {
+    run: u64,
+    msg: Arc<Broadcast>,
+    /// What the publisher is told, the broadcast isn't sent out when it failed
+    pub result: Result<(), Error>,
+}
+
+impl Appended {
+    fn new(run: u64, msg: Arc<Broadcast>, result: io::Result<()>) -> Self {
+        let result = result.map_err(|err| Error::Unjournaled(msg.channel().to_string(), err));
+        Self { run, msg, result }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(capacity: usize, count: u64) -> History {
+        let mut history = History::new(capacity);
+        for seq in 1..=count {
+            let msg = Publication::new(1, seq.to_string().into());
+            let appended = history.append("room", msg).try_written().unwrap();
+            history.release(&appended);
+        }
+        history
+    }
+
+    fn seqs(replayed: Vec<Arc<Broadcast>>) -> Vec<u64> {
+        replayed.iter().filter_map(|msg| msg.seq()).collect()
+    }
+
+    #[test]
+    fn keeps_the_last_broadcasts() {
+        let history = history(3, 5);
+
+        assert_eq!(seqs(history.replay(Replay::Last(10))), vec![3, 4, 5]);
+        assert_eq!(seqs(history.replay(Replay::Last(2))), vec![4, 5]);
+        assert_eq!(seqs(history.replay(Replay::Last(0))), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn replays_everything_after_a_sequence_number() {
+        let history = history(3, 5);
+
+        assert_eq!(seqs(history.replay(Replay::Since(3))), vec![4, 5]);
+        assert_eq!(seqs(history.replay(Replay::Since(0))), vec![3, 4, 5]);
+        assert_eq!(seqs(history.replay(Replay::Since(5))), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn resuming_replays_everything_of_a_new_run() {
+        let history = history(3, 5);
+        let cursor = |epoch| Cursor { epoch, seq: 3 };
+
+        assert_eq!(
+            seqs(history.replay(Replay::Resume(cursor(history.epoch())))),
+            vec![4, 5]
+        );
+        assert_eq!(
+            seqs(history.replay(Replay::Resume(cursor(History::new(3).epoch())))),
+            vec![3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn broadcasts_are_released_in_order_once_written() {
+        let mut history = History::new(10);
+        let mut append = |data: &str| history.append("room", Publication::new(1, data.into()));
+        let (first, second, third) = (append("a"), append("b"), append("c"));
+        let failed = Appended::new(first.run, first.msg, Err(io::Error::other("disk full")));
+
+        assert!(seqs(history.release(&second.try_written().unwrap())).is_empty());
+        assert_eq!(seqs(history.release(&failed)), vec![2]);
+        assert_eq!(
+            seqs(history.release(&third.try_written().unwrap())),
+            vec![3]
+        );
+        assert_eq!(seqs(history.replay(Replay::Since(0))), vec![2, 3]);
+        assert_eq!(history.seq(), 3);
+
+        // A channel started over ignores what the journal hands back to the old one
+        let mut old = History::new(10);
+        let stale = old.append("room", Publication::new(1, "d".into()));
+        assert!(history.release(&stale.try_written().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn since_wins_over_history() {
+        assert_eq!(Replay::new(Some(10), Some(3)), Some(Replay::Since(3)));
+        assert_eq!(Replay::new(Some(10), None), Some(Replay::Last(10)));
+        assert_eq!(Replay::new(None, None), None);
+        assert!(history(0, 5).replay(Replay::Last(10)).is_empty());
+    }
+}
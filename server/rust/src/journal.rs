@@ -0,0 +1,679 @@
+//! An append-only journal of every channel's broadcasts on disk, shared by every backend.
+//!
+//! Off unless `[history.journal] dir` is set. Every channel then has each broadcast it
+//! stamps written to its own directory below it, one line per broadcast holding its
+//! [crate::protocol::Event::Message] envelope. The journal is split into segments named
+//! after the sequence number of their first broadcast: a new one is started once the
+//! last one holds [Config::segment_bytes], and the oldest ones are deleted once they
+//! are past [Config::retention_bytes] or [Config::retention_secs].
+//!
+//! A channel starting up carries on from the last sequence number in its journal and
+//! fills its [crate::history::History] from it, so clients resuming after a restart see
+//! one continuous history. The server looks through every journal once when it starts,
+//! see [recover], cutting off a last line torn by a crash.
+//!
+//! A channel hands its broadcasts to a [Writer], which appends them on a blocking
+//! thread, so neither the channel nor anything it locks waits for the disk. It only
+//! fans a broadcast out once the writer says it was written, so no client sees one
+//! that isn't in the journal yet, see [crate::history::History::append]. A journal
+//! is only opened again once its last writer wrote everything and closed it, so a
+//! channel started right after it stopped carries on where it really stopped.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot, OwnedMutexGuard};
+use tokio::task;
+
+use crate::config;
+use crate::protocol::Broadcast;
+
+/// When a journal is flushed to the disk. Writes reach the OS right away, so a crash of
+/// the server alone loses nothing either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every broadcast
+    Always,
+    /// After this many broadcasts, and when a segment is full
+    Every(u32),
+    /// Whenever the OS gets to it
+    Never,
+}
+
+/// Parses `always`, `every:N` or `never`
+impl FromStr for Fsync {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Fsync::Always),
+            "never" => Ok(Fsync::Never),
+            _ => match s.strip_prefix("every:").map(str::parse) {
+                Some(Ok(count)) if count > 0 => Ok(Fsync::Every(count)),
+                _ => Err(format!("unknown fsync policy `{s}`")),
+            },
+        }
+    }
+}
+
+/// The `[history.journal]` section of the config file
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where the channels keep their journals, off when not set
+    pub dir: Option<PathBuf>,
+    #[serde(deserialize_with = "config::parse")]
+    pub fsync: Fsync,
+    /// How big a segment gets before the next one is started
+    pub segment_bytes: u64,
+    /// How big a channel's journal gets before its oldest segments are deleted, 0 keeps
+    /// them all
+    pub retention_bytes: u64,
+    /// How long a segment is kept after its last broadcast, 0 keeps it forever
+    pub retention_secs: u64,
+}
+
+impl Config {
+    /// Overrides the file with `JOURNAL_DIR`, `JOURNAL_FSYNC`, `JOURNAL_SEGMENT_BYTES`,
+    /// `JOURNAL_RETENTION_BYTES` and `JOURNAL_RETENTION_SECS`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(dir) = config::env("JOURNAL_DIR")? {
+            self.dir = Some(dir);
+        }
+        if let Some(fsync) = config::env("JOURNAL_FSYNC")? {
+            self.fsync = fsync;
+        }
+        if let Some(bytes) = config::env("JOURNAL_SEGMENT_BYTES")? {
+            self.segment_bytes = bytes;
+        }
+        if let Some(bytes) = config::env("JOURNAL_RETENTION_BYTES")? {
+            self.retention_bytes = bytes;
+        }
+        if let Some(secs) = config::env("JOURNAL_RETENTION_SECS")? {
+            self.retention_secs = secs;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            fsync: Fsync::Never,
+            segment_bytes: 16 << 20,
+            retention_bytes: 1 << 30,
+            retention_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// Opens every journal once so torn lines are cut off, and ids handed out from now on
+/// are higher than every one in them. Returns how many journals there are.
+pub fn recover(config: &Config) -> io::Result<usize> {
+    let Some(dir) = &config.dir else {
+        return Ok(0);
+    };
+    fs::create_dir_all(dir)?;
+
+    let mut recovered = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            Journal::open_dir(config, path)?;
+            recovered += 1;
+        }
+    }
+
+    Ok(recovered)
+}
+
+/// One file of a journal
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    /// The sequence number of its first broadcast
+    first_seq: u64,
+    bytes: u64,
+}
+
+impl Segment {
+    fn new(dir: &Path, first_seq: u64) -> Self {
+        Self {
+            path: dir.join(format!("{first_seq:020}.log")),
+            first_seq,
+            bytes: 0,
+        }
+    }
+
+    /// Every broadcast in the segment, along with how many bytes they take up. Reading
+    /// stops at the first line that isn't a whole broadcast, a write torn in the middle
+    /// of a character included.
+    fn read(&self) -> io::Result<(Vec<Arc<Broadcast>>, u64)> {
+        let contents = fs::read(&self.path)?;
+        let mut broadcasts = Vec::new();
+        let mut valid = 0;
+        for line in contents.split_inclusive(|byte| *byte == b'\n') {
+            let Some(msg) = line
+                .strip_suffix(b"\n")
+                .and_then(|line| std::str::from_utf8(line).ok())
+                .and_then(Broadcast::parse)
+            else {
+                break;
+            };
+            broadcasts.push(msg);
+            valid += line.len() as u64;
+        }
+
+        Ok((broadcasts, valid))
+    }
+
+    fn expired(&self, retention: Duration) -> bool {
+        fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .is_ok_and(|age| age > retention)
+            })
+    }
+}
+
+/// The journal of one channel
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    config: Config,
+    /// Oldest first, broadcasts are appended to the last one
+    segments: VecDeque<Segment>,
+    file: File,
+    /// The sequence number of the last broadcast
+    seq: u64,
+    /// Broadcasts appended since the last fsync
+    unsynced: u32,
+}
+
+impl Journal {
+    /// Opens the journal in `dir`, starting a new one when there is none yet
+    fn open_dir(config: &Config, dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut segments = VecDeque::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let first_seq = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".log")?.parse().ok());
+            if let Some(first_seq) = first_seq {
+                let bytes = fs::metadata(&path)?.len();
+                segments.push_back(Segment {
+                    path,
+                    first_seq,
+                    bytes,
+                });
+            }
+        }
+        segments
+            .make_contiguous()
+            .sort_by_key(|segment| segment.first_seq);
+        if segments.is_empty() {
+            segments.push_back(Segment::new(&dir, 1));
+        }
+
+        // Only the last segment was being written to, a crash can only have torn it
+        let last = segments.back_mut().expect("there is a segment");
+        let (broadcasts, valid) = if last.path.exists() {
+            last.read()?
+        } else {
+            (Vec::new(), 0)
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&last.path)?;
+        if valid < last.bytes {
+            println!("Cutting off a torn line in {}", last.path.display());
+            file.set_len(valid)?;
+        }
+        last.bytes = valid;
+        let seq = broadcasts
+            .last()
+            .and_then(|msg| msg.seq())
+            .unwrap_or(last.first_seq - 1);
+
+        Ok(Self {
+            dir,
+            config: config.clone(),
+            segments,
+            file,
+            seq,
+            unsynced: 0,
+        })
+    }
+
+    /// The sequence number of the last broadcast, 0 when there is none
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The last `count` broadcasts, oldest first
+    pub fn tail(&self, count: usize) -> io::Result<Vec<Arc<Broadcast>>> {
+        let mut tail = VecDeque::new();
+        for segment in self.segments.iter().rev() {
+            if tail.len() >= count {
+                break;
+            }
+            let (broadcasts, _) = segment.read()?;
+            for msg in broadcasts.into_iter().rev() {
+                if tail.len() == count {
+                    break;
+                }
+                tail.push_front(msg);
+            }
+        }
+
+        Ok(tail.into())
+    }
+
+    pub fn append(&mut self, msg: &Broadcast) -> io::Result<()> {
+        let line = format!("{}\n", msg.event());
+        let last = self.segments.back().expect("there is a segment");
+        if last.bytes > 0 && last.bytes + line.len() as u64 > self.config.segment_bytes {
+            self.roll(msg.seq().unwrap_or(self.seq + 1))?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.segments.back_mut().expect("there is a segment").bytes += line.len() as u64;
+        if let Some(seq) = msg.seq() {
+            self.seq = seq;
+        }
+
+        self.unsynced += 1;
+        match self.config.fsync {
+            Fsync::Always => self.sync(),
+            Fsync::Every(count) if self.unsynced >= count => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+
+        Ok(())
+    }
+
+    /// Start a new segment with the broadcast numbered `first_seq`
+    fn roll(&mut self, first_seq: u64) -> io::Result<()> {
+        if self.config.fsync != Fsync::Never {
+            self.sync()?;
+        }
+        let segment = Segment::new(&self.dir, first_seq);
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&segment.path)?;
+        self.segments.push_back(segment);
+        self.retain();
+
+        Ok(())
+    }
+
+    /// Delete the oldest segments past the retention, never the one written to
+    fn retain(&mut self) {
+        let retention = Duration::from_secs(self.config.retention_secs);
+        while self.segments.len() > 1 {
+            let total: u64 = self.segments.iter().map(|segment| segment.bytes).sum();
+            let oldest = &self.segments[0];
+            let too_big = self.config.retention_bytes > 0 && total > self.config.retention_bytes;
+            let too_old = self.config.retention_secs > 0 && oldest.expired(retention);
+            if !too_big && !too_old {
+                break;
+            }
+            if let Err(err) = fs::remove_file(&oldest.path) {
+                println!("Failed to delete {}: {err}", oldest.path.display());
+                break;
+            }
+            self.segments.pop_front();
+        }
+    }
+}
+
+/// The journals being written to, each with a lock its [Writer] holds until it closed it
+static WRITING: Mutex<BTreeMap<PathBuf, Arc<tokio::sync::Mutex<()>>>> = Mutex::new(BTreeMap::new());
+
+fn writing() -> MutexGuard<'static, BTreeMap<PathBuf, Arc<tokio::sync::Mutex<()>>>> {
+    WRITING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Waits until every journal was closed, with everything handed to its writer written
+pub async fn closed() {
+    let locks: Vec<_> = writing().values().cloned().collect();
+    for lock in locks {
+        let _closed = lock.lock().await;
+    }
+}
+
+/// Holds a journal while it is open
+#[derive(Debug)]
+struct Lock {
+    dir: PathBuf,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Lock {
+    /// Waits for the last writer of the journal in `dir` to close it
+    async fn acquire(dir: PathBuf) -> Self {
+        let lock = writing().entry(dir.clone()).or_default().clone();
+        let guard = lock.lock_owned().await;
+
+        Self {
+            dir,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        self.guard = None;
+        let mut writing = writing();
+        if writing
+            .get(&self.dir)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            writing.remove(&self.dir);
+        }
+    }
+}
+
+/// How many broadcasts a [Writer] appends in one go at most
+const BATCH: usize = 256;
+
+/// How often a [Writer] deletes segments past the retention while it waits
+const RETAIN_EVERY: Duration = Duration::from_secs(60);
+
+/// A broadcast handed to a [Writer], along with who waits for it to be written
+type Entry = (Arc<Broadcast>, oneshot::Sender<io::Result<()>>);
+
+/// Appends to the journal of a channel. Dropping it closes the journal once everything
+/// handed to it was written.
+#[derive(Debug)]
+pub struct Writer {
+    sender: mpsc::UnboundedSender<Entry>,
+}
+
+impl Writer {
+    /// Opens the channel's journal once its last writer closed it, along with the
+    /// sequence number of the last broadcast in it and its last `count` broadcasts.
+    /// Returns [None] when journals are off.
+    pub async fn open(
+        config: &Config,
+        channel: &str,
+        count: usize,
+    ) -> io::Result<Option<(Self, u64, Vec<Arc<Broadcast>>)>> {
+        let Some(dir) = &config.dir else {
+            return Ok(None);
+        };
+        let dir = dir.join(dir_name(channel));
+        let lock = Lock::acquire(dir.clone()).await;
+
+        let config = config.clone();
+        let (journal, tail) = task::spawn_blocking(move || {
+            let journal = Journal::open_dir(&config, dir)?;
+            let tail = journal.tail(count)?;
+            Ok::<_, io::Error>((journal, tail))
+        })
+        .await
+        .map_err(io::Error::other)??;
+        let seq = journal.seq();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write(journal, receiver, lock));
+
+        Ok(Some((Self { sender }, seq, tail)))
+    }
+
+    /// Hand a broadcast over to be appended, the receiver gets whether it was written
+    /// and flushed as [Config::fsync] asks for
+    pub fn append(&self, msg: &Arc<Broadcast>) -> oneshot::Receiver<io::Result<()>> {
+        let (written, receiver) = oneshot::channel();
+        // The writer only stops once every sender is gone
+        let _ = self.sender.send((msg.clone(), written));
+        receiver
+    }
+}
+
+/// Appends everything the channel hands over, a batch at a time on a blocking thread,
+/// until its [Writer] is dropped. Segments past [Config::retention_secs] are deleted
+/// once a minute as well, so a quiet channel doesn't keep them until it rolls.
+async fn write(mut journal: Journal, mut receiver: mpsc::UnboundedReceiver<Entry>, _lock: Lock) {
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut retain = tokio::time::interval(RETAIN_EVERY);
+    loop {
+        let entries = tokio::select! {
+            _ = retain.tick() => None,
+            received = receiver.recv_many(&mut batch, BATCH) => match received {
+                0 => return,
+                _ => Some(std::mem::take(&mut batch)),
+            },
+        };
+        let written = task::spawn_blocking(move || {
+            match entries {
+                Some(entries) => {
+                    for (msg, written) in entries {
+                        let appended = journal.append(&msg);
+                        if let Err(err) = &appended {
+                            println!("Failed to journal a message of {}: {err}", msg.channel());
+                        }
+                        // The publisher stopped waiting when nobody gets it
+                        let _ = written.send(appended);
+                    }
+                }
+                None => journal.retain(),
+            }
+            journal
+        });
+        journal = match written.await {
+            Ok(journal) => journal,
+            Err(err) => {
+                println!("Journal writer failed: {err}");
+                return;
+            }
+        };
+    }
+}
+
+/// Channel names may hold anything, everything but letters, digits, `-` and `_` is
+/// escaped like in urls so no name can point outside the journal directory
+fn dir_name(channel: &str) -> String {
+    let mut name = String::with_capacity(channel.len());
+    for byte in channel.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::protocol::Publication;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    /// A journal config in a directory of its own, removed when dropped
+    struct Scratch(Config);
+
+    impl Scratch {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "ws-server-journal-{}-{}",
+                std::process::id(),
+                NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+            ));
+            Self(Config {
+                dir: Some(dir),
+                ..Config::default()
+            })
+        }
+
+        fn dir(&self) -> PathBuf {
+            self.0.dir.clone().unwrap().join("room")
+        }
+
+        fn open(&self) -> Journal {
+            Journal::open_dir(&self.0, self.dir()).unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.dir.as_ref().unwrap());
+        }
+    }
+
+    fn append(journal: &mut Journal, seqs: std::ops::RangeInclusive<u64>) {
+        for seq in seqs {
+            let msg = Publication::new(1, format!("message {seq}").into());
+            journal
+                .append(&Broadcast::new("room", Some(seq), msg))
+                .unwrap();
+        }
+    }
+
+    fn seqs(broadcasts: Vec<Arc<Broadcast>>) -> Vec<u64> {
+        broadcasts.iter().filter_map(|msg| msg.seq()).collect()
+    }
+
+    fn segments(scratch: &Scratch) -> usize {
+        fs::read_dir(scratch.dir()).unwrap().count()
+    }
+
+    #[test]
+    fn reopening_carries_on_where_it_stopped() {
+        let scratch = Scratch::new();
+        let mut journal = scratch.open();
+        assert_eq!(journal.seq(), 0);
+        append(&mut journal, 1..=5);
+        drop(journal);
+
+        let journal = scratch.open();
+        assert_eq!(journal.seq(), 5);
+        assert_eq!(seqs(journal.tail(3).unwrap()), vec![3, 4, 5]);
+        assert_eq!(seqs(journal.tail(10).unwrap()), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn full_segments_roll_over_and_old_ones_go() {
+        let mut scratch = Scratch::new();
+        // Every broadcast gets a segment of its own, and two of them are kept
+        let line = Broadcast::new("room", Some(1), Publication::new(1, "message 1".into()))
+            .event()
+            .len() as u64;
+        scratch.0.segment_bytes = line;
+        scratch.0.retention_bytes = 2 * line + 1;
+        let mut journal = scratch.open();
+
+        append(&mut journal, 1..=5);
+
+        assert_eq!(segments(&scratch), 2);
+        assert_eq!(seqs(journal.tail(10).unwrap()), vec![4, 5]);
+        drop(journal);
+        let journal = scratch.open();
+        assert_eq!(journal.seq(), 5);
+    }
+
+    #[test]
+    fn torn_lines_are_cut_off() {
+        let scratch = Scratch::new();
+        let mut journal = scratch.open();
+        append(&mut journal, 1..=2);
+        drop(journal);
+        let path = scratch.dir().join(format!("{:020}.log", 1));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"op":"message","channel":"room","id":"#)
+            .unwrap();
+
+        assert_eq!(recover(&scratch.0).unwrap(), 1);
+        let mut journal = scratch.open();
+        assert_eq!(journal.seq(), 2);
+        append(&mut journal, 3..=3);
+        assert_eq!(seqs(journal.tail(10).unwrap()), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn torn_characters_are_cut_off() {
+        let scratch = Scratch::new();
+        let mut journal = scratch.open();
+        append(&mut journal, 1..=2);
+        drop(journal);
+        let path = scratch.dir().join(format!("{:020}.log", 1));
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        // The first two of the three bytes of `€`
+        file.write_all(b"{\"op\":\"message\",\"data\":\"\xE2\x82")
+            .unwrap();
+
+        assert_eq!(recover(&scratch.0).unwrap(), 1);
+        let mut journal = scratch.open();
+        assert_eq!(journal.seq(), 2);
+        append(&mut journal, 3..=3);
+        assert_eq!(seqs(journal.tail(10).unwrap()), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn writers_wait_for_the_last_one_to_close_the_journal() {
+        let scratch = Scratch::new();
+        let (writer, seq, _) = Writer::open(&scratch.0, "room", 10).await.unwrap().unwrap();
+        assert_eq!(seq, 0);
+        for seq in 1..=500 {
+            let msg = Publication::new(1, format!("message {seq}").into());
+            writer.append(&Broadcast::new("room", Some(seq), msg));
+        }
+        drop(writer);
+
+        let (_writer, seq, tail) = Writer::open(&scratch.0, "room", 3).await.unwrap().unwrap();
+        assert_eq!(seq, 500);
+        assert_eq!(seqs(tail), vec![498, 499, 500]);
+        assert!(Writer::open(&Config::default(), "room", 3)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn writer_answers_once_the_broadcast_is_written() {
+        let scratch = Scratch::new();
+        let (writer, _, _) = Writer::open(&scratch.0, "room", 10).await.unwrap().unwrap();
+        let msg = Broadcast::new("room", Some(1), Publication::new(1, "hello".into()));
+        writer.append(&msg).await.unwrap().unwrap();
+
+        let (broadcasts, _) = Segment::new(&scratch.dir(), 1).read().unwrap();
+        assert_eq!(seqs(broadcasts), vec![1]);
+    }
+
+    #[test]
+    fn channel_names_stay_inside_the_directory() {
+        assert_eq!(dir_name("sports.nfl"), "sports%2Enfl");
+        assert_eq!(dir_name("../etc"), "%2E%2E%2Fetc");
+        assert_eq!(dir_name("a_b-C9"), "a_b-C9");
+    }
+
+    #[test]
+    fn parses_fsync_policies() {
+        assert_eq!("always".parse(), Ok(Fsync::Always));
+        assert_eq!("every:10".parse(), Ok(Fsync::Every(10)));
+        assert_eq!("never".parse(), Ok(Fsync::Never));
+        assert!("every:0".parse::<Fsync>().is_err());
+        assert!("sometimes".parse::<Fsync>().is_err());
+    }
+}
@@ -1,8 +1,68 @@
+mod config;
+mod error;
+mod history;
+mod journal;
+mod outbox;
+mod presence;
+mod protocol;
 mod ractor;
+mod server;
 mod tokio_actors;
+mod tokio_broadcast;
+mod topic;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum BackendKind {
+    Ractor,
+    TokioActors,
+    TokioBroadcast,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Websocket pub/sub server")]
+struct Args {
+    /// Which broker implementation to run
+    #[arg(long, value_enum, default_value_t = BackendKind::Ractor)]
+    backend: BackendKind,
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:8888")]
+    bind: SocketAddr,
+    /// A TOML file with the balancer and outbox settings
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
 
 #[tokio::main]
 async fn main() {
-    // tokio_actors::run().await;
-    ractor::run().await;
+    let args = Args::parse();
+    let config = match config::Config::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid config: {err}");
+            std::process::exit(1);
+        }
+    };
+    match journal::recover(&config.history.journal) {
+        Ok(0) => {}
+        Ok(recovered) => println!("Recovered the journals of {recovered} channels"),
+        Err(err) => {
+            eprintln!("Failed to recover the journals: {err}");
+            std::process::exit(1);
+        }
+    }
+
+    match args.backend {
+        BackendKind::Ractor => server::serve::<ractor::Ractor>(args.bind, config).await,
+        BackendKind::TokioActors => {
+            server::serve::<tokio_actors::TokioActors>(args.bind, config).await
+        }
+        BackendKind::TokioBroadcast => {
+            server::serve::<tokio_broadcast::TokioBroadcast>(args.bind, config).await
+        }
+    }
 }
@@ -0,0 +1,642 @@
+//! The bounded outbound queue in front of every websocket, shared by every backend.
+//!
+//! Frames for a client are queued here and written to its socket by a task of their
+//! own, so a client that reads slowly only ever fills its own queue. What happens
+//! once the queue is full is decided by the [Policy].
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+use axum::extract::ws::{CloseFrame, Message};
+use axum::{Extension, Json};
+use futures::{Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::config;
+use crate::error::Error;
+use crate::protocol::Payload;
+
+/// What to do with a frame for a client whose queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Wait for room. The session stops taking deliveries off its mailbox meanwhile,
+    /// so broadcasts are dropped once that is full too, and counted as dropped here.
+    Block,
+    /// Make room by dropping the oldest queued frame
+    DropOldest,
+    /// Drop the new frame
+    DropNewest,
+    /// Drop the new frame, and disconnect the client once this many were dropped
+    Disconnect(u64),
+}
+
+/// Parses `block`, `drop-oldest`, `drop-newest` or `disconnect:N`
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Policy::Block),
+            "drop-oldest" => Ok(Policy::DropOldest),
+            "drop-newest" => Ok(Policy::DropNewest),
+            _ => match s.strip_prefix("disconnect:").map(str::parse) {
+                Some(Ok(after)) if after > 0 => Ok(Policy::Disconnect(after)),
+                _ => Err(format!("unknown outbox policy `{s}`")),
+            },
+        }
+    }
+}
+
+/// The `[outbox]` section of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How many frames are queued for a client before the [Policy] kicks in
+    pub capacity: usize,
+    #[serde(deserialize_with = "config::parse")]
+    pub policy: Policy,
+}
+
+impl Config {
+    /// Overrides the file with `OUTBOX_CAPACITY` and `OUTBOX_POLICY`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(capacity) = config::env("OUTBOX_CAPACITY")? {
+            self.capacity = capacity;
+        }
+        if let Some(policy) = config::env("OUTBOX_POLICY")? {
+            self.policy = policy;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            capacity: 500,
+            policy: Policy::DropOldest,
+        }
+    }
+}
+
+/// What happened to the frames of a connection so far
+#[derive(Debug, Default)]
+pub struct Counters {
+    queued: AtomicUsize,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    /// Broadcasts the client acknowledged, see [crate::server::ack]
+    acked: AtomicU64,
+    /// Broadcasts sent again because their ack took too long
+    redelivered: AtomicU64,
+    /// Milliseconds from sending each acknowledged broadcast until its ack, summed up
+    ack_ms_total: AtomicU64,
+    ack_ms_max: AtomicU64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Snapshot {
+    pub queued: usize,
+    pub sent: u64,
+    pub dropped: u64,
+    pub acked: u64,
+    pub redelivered: u64,
+    /// How long acks took on average, in milliseconds
+    pub ack_ms_mean: u64,
+    pub ack_ms_max: u64,
+}
+
+impl Counters {
+    pub fn snapshot(&self) -> Snapshot {
+        let acked = self.acked.load(Ordering::Relaxed);
+        Snapshot {
+            queued: self.queued.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            acked,
+            redelivered: self.redelivered.load(Ordering::Relaxed),
+            ack_ms_mean: self.ack_ms_total.load(Ordering::Relaxed) / acked.max(1),
+            ack_ms_max: self.ack_ms_max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The outbox [Config] and the [Counters] of every open connection, keyed by the
+/// peer address, handed to the websocket handlers as an [Extension]
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub config: Config,
+    pub connections: Arc<RwLock<HashMap<String, Arc<Counters>>>>,
+}
+
+impl Settings {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            connections: Default::default(),
+        }
+    }
+
+    pub fn register(&self, peer: String) -> Arc<Counters> {
+        let counters = Arc::new(Counters::default());
+        self.connections
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(peer, counters.clone());
+        counters
+    }
+
+    pub fn unregister(&self, peer: &str) {
+        self.connections
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(peer);
+    }
+}
+
+/// Serves the [Counters] of every open connection
+pub async fn stats(Extension(settings): Extension<Settings>) -> Json<HashMap<String, Snapshot>> {
+    let connections = settings
+        .connections
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|(peer, counters)| (peer.clone(), counters.snapshot()))
+        .collect();
+
+    Json(connections)
+}
+
+/// A queued frame. Data frames share their buffer with every other recipient of the
+/// broadcast while they are queued, but every recipient still copies it into a
+/// [Message] of its own right before it is written, as axum 0.7 messages own their
+/// data.
+enum Frame {
+    /// Numbered in the order they were queued, see [Outbox::written]
+    Data(u64, Payload),
+    Ping,
+    Close(CloseFrame<'static>),
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        match frame {
+            Frame::Data(_, Payload::Text(text)) => Message::Text(text.to_string()),
+            Frame::Data(_, Payload::Binary(data)) => Message::Binary(data.to_vec()),
+            Frame::Ping => Message::Ping(Vec::new()),
+            Frame::Close(frame) => Message::Close(Some(frame)),
+        }
+    }
+}
+
+struct Shared {
+    queue: Mutex<VecDeque<Frame>>,
+    config: Config,
+    counters: Arc<Counters>,
+    /// Signals the writer a frame was queued or the outbox closed
+    pushed: Notify,
+    /// Signals a blocked push that the writer made room
+    popped: Notify,
+    closed: AtomicBool,
+    /// The number of the last data frame queued
+    numbered: AtomicU64,
+    /// The number of the last data frame written to the socket
+    written: AtomicU64,
+    /// Why writing to the socket failed, nothing is queued anymore once it did
+    failed: Mutex<Option<String>>,
+}
+
+impl Shared {
+    fn pop(&self) -> Option<Frame> {
+        let mut queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let msg = queue.pop_front();
+        self.counters.queued.store(queue.len(), Ordering::Relaxed);
+        msg
+    }
+}
+
+/// Queues pings for an [Outbox] without borrowing it, so they still go out while a push
+/// waits for room
+#[derive(Clone)]
+pub struct Pinger(Arc<Shared>);
+
+impl Pinger {
+    /// Queue a ping after everything already queued, whatever the [Policy]
+    pub fn ping(&self) {
+        let shared = &self.0;
+        let mut queue = shared.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        queue.push_back(Frame::Ping);
+        shared.counters.queued.store(queue.len(), Ordering::Relaxed);
+        shared.pushed.notify_one();
+    }
+}
+
+pub struct Outbox {
+    shared: Arc<Shared>,
+    writer: JoinHandle<()>,
+}
+
+impl Outbox {
+    /// Spawns the task writing queued frames to `sink`, it stops once the outbox is
+    /// dropped and everything queued was written
+    pub fn new<S>(sink: S, config: Config, counters: Arc<Counters>) -> Self
+    where
+        S: Sink<Message> + Unpin + Send + 'static,
+        S::Error: fmt::Display,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            config,
+            counters,
+            pushed: Notify::new(),
+            popped: Notify::new(),
+            closed: AtomicBool::new(false),
+            numbered: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            failed: Mutex::new(None),
+        });
+        let writer = tokio::spawn(write(shared.clone(), sink));
+
+        Self { shared, writer }
+    }
+
+    /// Queue a text or binary frame, applying the [Policy] when the queue is full
+    pub async fn push(&self, frame: Payload) -> Result<(), Error> {
+        self.push_numbered(frame).await.map(drop)
+    }
+
+    /// Like [Outbox::push], returns the number of the frame to compare with
+    /// [Outbox::written], or [None] when the frame was dropped right away
+    pub async fn push_numbered(&self, frame: Payload) -> Result<Option<u64>, Error> {
+        let mut msg = frame;
+        loop {
+            match self.try_push(msg)? {
+                Ok(number) => return Ok(number),
+                Err(blocked) => {
+                    msg = blocked;
+                    self.shared.popped.notified().await;
+                }
+            }
+        }
+    }
+
+    /// The number of the last frame written to the socket, every frame queued before it
+    /// was written or dropped too
+    pub fn written(&self) -> u64 {
+        self.shared.written.load(Ordering::Relaxed)
+    }
+
+    /// Returns the frame back when it has to wait for room, its number otherwise, see
+    /// [Outbox::push_numbered]
+    fn try_push(&self, msg: Payload) -> Result<Result<Option<u64>, Payload>, Error> {
+        let shared = &self.shared;
+        if shared.closed.load(Ordering::Relaxed) {
+            return Err(Error::Closed);
+        }
+        if let Some(err) = shared
+            .failed
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            return Err(Error::Socket(axum::Error::new(err.clone())));
+        }
+
+        let mut queue = shared.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let full = queue.len() >= shared.config.capacity;
+        match shared.config.policy {
+            Policy::Block if full => return Ok(Err(msg)),
+            Policy::DropOldest if full => {
+                shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                // Pings and close frames aren't data, they stay. Without any data to make
+                // room with, the new frame goes instead.
+                let oldest = queue
+                    .iter()
+                    .position(|frame| matches!(frame, Frame::Data(..)));
+                match oldest {
+                    Some(oldest) => drop(queue.remove(oldest)),
+                    None => return Ok(Ok(None)),
+                }
+            }
+            Policy::DropNewest if full => {
+                shared.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                return Ok(Ok(None));
+            }
+            Policy::Disconnect(after) if full => {
+                let dropped = shared.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped >= after {
+                    return Err(Error::SlowConsumer);
+                }
+                return Ok(Ok(None));
+            }
+            _ => {}
+        }
+        let number = shared.numbered.fetch_add(1, Ordering::Relaxed) + 1;
+        queue.push_back(Frame::Data(number, msg));
+        shared.counters.queued.store(queue.len(), Ordering::Relaxed);
+        shared.pushed.notify_one();
+
+        Ok(Ok(Some(number)))
+    }
+
+    /// Count frames the backend skipped before they got here as dropped, a client
+    /// with [Policy::Disconnect] is disconnected once too many were
+    pub fn missed(&self, count: u64) -> Result<(), Error> {
+        let counters = &self.shared.counters;
+        let dropped = counters.dropped.fetch_add(count, Ordering::Relaxed) + count;
+        match self.shared.config.policy {
+            Policy::Disconnect(after) if dropped >= after => Err(Error::SlowConsumer),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a broadcast the client acknowledged `latency` after it was first sent
+    pub fn acked(&self, latency: Duration) {
+        let counters = &self.shared.counters;
+        let ms = latency.as_millis() as u64;
+        counters.acked.fetch_add(1, Ordering::Relaxed);
+        counters.ack_ms_total.fetch_add(ms, Ordering::Relaxed);
+        counters.ack_ms_max.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    /// Count broadcasts sent again for want of an ack
+    pub fn redelivered(&self, count: u64) {
+        let counters = &self.shared.counters;
+        counters.redelivered.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Queue a close frame after everything already queued, whatever the [Policy],
+    /// and stop taking frames, every push fails from now on
+    pub fn close(&self, frame: CloseFrame<'static>) {
+        let shared = &self.shared;
+        shared
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back(Frame::Close(frame));
+        shared.closed.store(true, Ordering::Relaxed);
+        shared.pushed.notify_one();
+        // Wake up a push blocked on a full queue
+        shared.popped.notify_one();
+    }
+
+    pub fn pinger(&self) -> Pinger {
+        Pinger(self.shared.clone())
+    }
+
+    /// Stop taking frames and wait until everything queued was written, or writing
+    /// to the socket failed
+    pub async fn flush(mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        self.shared.pushed.notify_one();
+        let _ = (&mut self.writer).await;
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Relaxed);
+        self.shared.pushed.notify_one();
+    }
+}
+
+async fn write<S>(shared: Arc<Shared>, mut sink: S)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: fmt::Display,
+{
+    loop {
+        let Some(msg) = shared.pop() else {
+            if shared.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            shared.pushed.notified().await;
+            continue;
+        };
+        shared.popped.notify_one();
+
+        let closing = matches!(msg, Frame::Close(_));
+        let number = match msg {
+            Frame::Data(number, _) => Some(number),
+            _ => None,
+        };
+        if let Err(err) = sink.send(msg.into()).await {
+            *shared.failed.lock().unwrap_or_else(PoisonError::into_inner) = Some(err.to_string());
+            // Wake up a push blocked on a socket that won't take anything anymore
+            shared.popped.notify_one();
+            return;
+        }
+        shared.counters.sent.fetch_add(1, Ordering::Relaxed);
+        if let Some(number) = number {
+            shared.written.store(number, Ordering::Relaxed);
+        }
+        if closing {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+
+    fn text(msg: &str) -> Message {
+        Message::Text(msg.to_string())
+    }
+
+    /// The writer task doesn't get to run before the test awaits something, so every
+    /// frame pushed until then stays queued
+    fn outbox(capacity: usize, policy: Policy) -> (Outbox, mpsc::Receiver<Message>) {
+        let (sink, socket) = mpsc::channel(16);
+        let outbox = Outbox::new(sink, Config { capacity, policy }, Arc::default());
+        (outbox, socket)
+    }
+
+    async fn written(outbox: Outbox, socket: mpsc::Receiver<Message>) -> (Vec<Message>, Snapshot) {
+        let counters = outbox.shared.counters.clone();
+        drop(outbox);
+        let frames = socket.collect().await;
+        (frames, counters.snapshot())
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("block".parse(), Ok(Policy::Block));
+        assert_eq!("drop-oldest".parse(), Ok(Policy::DropOldest));
+        assert_eq!("drop-newest".parse(), Ok(Policy::DropNewest));
+        assert_eq!("disconnect:10".parse(), Ok(Policy::Disconnect(10)));
+        assert!("disconnect:0".parse::<Policy>().is_err());
+        assert!("drop".parse::<Policy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_frames() {
+        let (outbox, socket) = outbox(2, Policy::DropOldest);
+        for msg in ["a", "b", "c"] {
+            outbox.push(msg.into()).await.unwrap();
+        }
+
+        let (frames, counters) = written(outbox, socket).await;
+        assert_eq!(frames, vec![text("b"), text("c")]);
+        assert_eq!(counters.dropped, 1);
+        assert_eq!(counters.sent, 2);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_pings() {
+        let (outbox, socket) = outbox(2, Policy::DropOldest);
+        outbox.pinger().ping();
+        for msg in ["a", "b"] {
+            outbox.push(msg.into()).await.unwrap();
+        }
+
+        let (frames, counters) = written(outbox, socket).await;
+        assert_eq!(frames, vec![Message::Ping(Vec::new()), text("b")]);
+        assert_eq!(counters.dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_first_frames() {
+        let (outbox, socket) = outbox(2, Policy::DropNewest);
+        for msg in ["a", "b", "c"] {
+            outbox.push(msg.into()).await.unwrap();
+        }
+
+        let (frames, counters) = written(outbox, socket).await;
+        assert_eq!(frames, vec![text("a"), text("b")]);
+        assert_eq!(counters.dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_after_too_many_drops() {
+        let (outbox, _socket) = outbox(1, Policy::Disconnect(2));
+        outbox.push("a".into()).await.unwrap();
+        outbox.push("b".into()).await.unwrap();
+
+        assert!(matches!(
+            outbox.push("c".into()).await,
+            Err(Error::SlowConsumer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn missed_frames_count_as_dropped() {
+        let (outbox, socket) = outbox(1, Policy::Disconnect(5));
+        outbox.missed(3).unwrap();
+        assert!(matches!(outbox.missed(2), Err(Error::SlowConsumer)));
+
+        let (_, counters) = written(outbox, socket).await;
+        assert_eq!(counters.dropped, 5);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (outbox, socket) = outbox(1, Policy::Block);
+        for msg in ["a", "b", "c"] {
+            outbox.push(msg.into()).await.unwrap();
+        }
+
+        let (frames, counters) = written(outbox, socket).await;
+        assert_eq!(frames, vec![text("a"), text("b"), text("c")]);
+        assert_eq!(counters.dropped, 0);
+    }
+
+    #[tokio::test]
+    async fn written_frames_are_counted_by_number() {
+        let (sink, mut socket) = mpsc::channel(16);
+        let config = Config {
+            capacity: 1,
+            policy: Policy::DropNewest,
+        };
+        let outbox = Outbox::new(sink, config, Arc::default());
+        assert_eq!(outbox.push_numbered("a".into()).await.unwrap(), Some(1));
+        assert_eq!(outbox.push_numbered("b".into()).await.unwrap(), None);
+        assert_eq!(outbox.written(), 0);
+
+        assert_eq!(socket.next().await, Some(text("a")));
+        assert_eq!(outbox.written(), 1);
+        assert_eq!(outbox.push_numbered("c".into()).await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn acks_are_timed() {
+        let (outbox, socket) = outbox(1, Policy::Block);
+        outbox.acked(Duration::from_millis(30));
+        outbox.acked(Duration::from_millis(10));
+        outbox.redelivered(2);
+
+        let (_, counters) = written(outbox, socket).await;
+        assert_eq!((counters.acked, counters.redelivered), (2, 2));
+        assert_eq!((counters.ack_ms_mean, counters.ack_ms_max), (20, 30));
+    }
+
+    #[tokio::test]
+    async fn close_frame_goes_out_last() {
+        let (outbox, socket) = outbox(1, Policy::DropNewest);
+        outbox.push("a".into()).await.unwrap();
+        let frame = CloseFrame {
+            code: 1000,
+            reason: "bye".into(),
+        };
+        outbox.close(frame.clone());
+
+        let (frames, _) = written(outbox, socket).await;
+        assert_eq!(frames, vec![text("a"), Message::Close(Some(frame))]);
+    }
+
+    #[tokio::test]
+    async fn closed_outbox_takes_no_more_frames() {
+        let (outbox, socket) = outbox(1, Policy::Block);
+        outbox.push("a".into()).await.unwrap();
+        let frame = CloseFrame {
+            code: 1000,
+            reason: "bye".into(),
+        };
+
+        // A push waiting for room fails as well
+        let (blocked, ()) = tokio::join!(outbox.push("b".into()), async {
+            outbox.close(frame.clone())
+        });
+        assert!(matches!(blocked, Err(Error::Closed)));
+        assert!(matches!(outbox.push("c".into()).await, Err(Error::Closed)));
+        let (frames, _) = written(outbox, socket).await;
+        assert_eq!(frames, vec![text("a"), Message::Close(Some(frame))]);
+    }
+
+    #[tokio::test]
+    async fn flush_writes_everything_queued() {
+        let (outbox, socket) = outbox(2, Policy::Block);
+        outbox.push("a".into()).await.unwrap();
+        outbox.push("b".into()).await.unwrap();
+
+        outbox.flush().await;
+        let frames: Vec<Message> = socket.collect().await;
+        assert_eq!(frames, vec![text("a"), text("b")]);
+    }
+
+    #[tokio::test]
+    async fn failed_socket_fails_the_next_push() {
+        let (outbox, socket) = outbox(1, Policy::Block);
+        drop(socket);
+        outbox.push("a".into()).await.unwrap();
+        // The writer fails on "a", and wakes up the push waiting behind it
+        assert!(matches!(
+            outbox.push("b".into()).await,
+            Err(Error::Socket(_))
+        ));
+
+        assert!(matches!(
+            outbox.push("c".into()).await,
+            Err(Error::Socket(_))
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+//! Who is in a channel, shared by every backend.
+//!
+//! A connection that tells who its user is, with `?user=alice&meta={"name":"Alice"}`,
+//! is counted as a member of every channel it joins. Members are users, not
+//! connections: the channel broadcasts `{"op":"presence_join",...}` when the first
+//! connection of a user joins and `{"op":"presence_leave",...}` when the last one leaves.
+//! Connections without a user are not tracked.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::protocol::{Broadcast, Event};
+use crate::server::session::ConnectionId;
+
+/// Who a connection belongs to, set when it connects
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub user: String,
+    /// Whatever the client wants the other members to see, [Value::Null] when not given
+    pub meta: Value,
+}
+
+/// A user in a channel, as answered to a presence query
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Member {
+    pub user: String,
+    pub meta: Value,
+    /// How many of the user's connections are in the channel
+    pub connections: usize,
+}
+
+/// A connection of a user joined or left a channel, for backends that hand it to the
+/// channel as a message
+#[derive(Debug, Clone)]
+pub enum Change {
+    Join(ConnectionId, Arc<Identity>),
+    Leave(ConnectionId, Arc<Identity>),
+}
+
+struct Presence {
+    /// The metadata of the user's first connection
+    meta: Value,
+    connections: HashSet<ConnectionId>,
+}
+
+/// The members of one channel
+pub struct Members {
+    channel: String,
+    users: BTreeMap<String, Presence>,
+}
+
+impl Members {
+    pub fn new(channel: &str) -> Self {
+        Self {
+            channel: channel.to_string(),
+            users: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the [Event::PresenceJoin] to broadcast when this is the user's first
+    /// connection in the channel
+    pub fn join(&mut self, id: ConnectionId, identity: &Identity) -> Option<Arc<Broadcast>> {
+        let presence = self
+            .users
+            .entry(identity.user.clone())
+            .or_insert_with(|| Presence {
+                meta: identity.meta.clone(),
+                connections: HashSet::new(),
+            });
+        let first = presence.connections.is_empty();
+        presence.connections.insert(id);
+
+        first.then(|| {
+            let event = Event::PresenceJoin {
+                channel: &self.channel,
+                user: &identity.user,
+                meta: &presence.meta,
+            };
+            Broadcast::notice(&self.channel, event.encode())
+        })
+    }
+
+    /// Returns the [Event::PresenceLeave] to broadcast when this was the user's last
+    /// connection in the channel
+    pub fn leave(&mut self, id: ConnectionId, identity: &Identity) -> Option<Arc<Broadcast>> {
+        let presence = self.users.get_mut(&identity.user)?;
+        if !presence.connections.remove(&id) || !presence.connections.is_empty() {
+            return None;
+        }
+        self.users.remove(&identity.user);
+
+        let event = Event::PresenceLeave {
+            channel: &self.channel,
+            user: &identity.user,
+        };
+        Some(Broadcast::notice(&self.channel, event.encode()))
+    }
+
+    pub fn apply(&mut self, change: &Change) -> Option<Arc<Broadcast>> {
+        match change {
+            Change::Join(id, identity) => self.join(*id, identity),
+            Change::Leave(id, identity) => self.leave(*id, identity),
+        }
+    }
+
+    /// Every user in the channel, by name
+    pub fn list(&self) -> Vec<Member> {
+        self.users
+            .iter()
+            .map(|(user, presence)| Member {
+                user: user.clone(),
+                meta: presence.meta.clone(),
+                connections: presence.connections.len(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn identity(user: &str) -> Identity {
+        Identity {
+            user: user.to_string(),
+            meta: json!({ "name": user.to_uppercase() }),
+        }
+    }
+
+    #[test]
+    fn users_join_with_their_first_connection_and_leave_with_their_last() {
+        let mut members = Members::new("room");
+        let alice = identity("alice");
+
+        let joined = members.join(1, &alice).unwrap();
+        assert_eq!(
+            &*joined.event(),
+            r#"{"op":"presence_join","channel":"room","user":"alice","meta":{"name":"ALICE"}}"#
+        );
+        assert!(members.join(2, &alice).is_none());
+
+        assert!(members.leave(1, &alice).is_none());
+        let left = members.leave(2, &alice).unwrap();
+        assert_eq!(
+            &*left.event(),
+            r#"{"op":"presence_leave","channel":"room","user":"alice"}"#
+        );
+        assert!(members.list().is_empty());
+    }
+
+    #[test]
+    fn list_counts_connections_per_user() {
+        let mut members = Members::new("room");
+        members.join(1, &identity("bob"));
+        members.join(2, &identity("alice"));
+        members.join(3, &identity("alice"));
+
+        assert_eq!(
+            members.list(),
+            vec![
+                Member {
+                    user: "alice".to_string(),
+                    meta: json!({ "name": "ALICE" }),
+                    connections: 2,
+                },
+                Member {
+                    user: "bob".to_string(),
+                    meta: json!({ "name": "BOB" }),
+                    connections: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn leaving_twice_is_ignored() {
+        let mut members = Members::new("room");
+        let alice = identity("alice");
+        members.join(1, &alice);
+        members.join(2, &alice);
+
+        assert!(members.leave(1, &alice).is_none());
+        assert!(members.leave(1, &alice).is_none());
+        assert_eq!(members.list()[0].connections, 1);
+    }
+}
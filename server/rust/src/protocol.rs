@@ -0,0 +1,460 @@
+//! The JSON control protocol spoken over a websocket, shared by every backend.
+//!
+//! A client sends commands such as `{"op":"subscribe","channel":"x"}` and receives
+//! messages from channels it subscribed to as an envelope such as
+//! `{"op":"message","channel":"x","id":7,"seq":3,"sender":2,"timestamp":1700000000000,"data":"..."}`,
+//! see [Event::Message]. Subscriptions may use wildcard patterns such as `sports.*`,
+//! see [crate::topic]. Text frames that are not a command, and binary frames, are
+//! published to the channel in the url the socket connected to. Binary messages from
+//! that channel are delivered as binary frames, everywhere else their data is base64
+//! encoded, e.g. `{"op":"message",...,"data":"AAE=","encoding":"base64"}`.
+//!
+//! A connection can also message a single other one by its id, the `sender` of its
+//! messages, with `{"op":"direct","to":2,"data":"..."}`. A command that can't be carried
+//! out, or any JSON object with an `op` that isn't a valid command, is answered with
+//! `{"op":"error","message":"..."}`, see [Event::Error].
+//! Channels tell their members who else is there, see [crate::presence], and replay
+//! their last messages to connections joining them, see [crate::history]. A client
+//! whose socket dropped can pick up where it left off, see [crate::server::resume], and
+//! one that can't afford to lose messages acknowledges them with
+//! `{"op":"ack","channel":"x","seq":3}`, see [crate::server::ack].
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::presence::Member;
+use crate::server::session::ConnectionId;
+
+/// The sender of broadcasts the server makes itself, connection ids start at 1
+pub const SERVER: ConnectionId = 0;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Commands a client can send
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    /// Join a channel after replaying its `history` or everything `since`, see
+    /// [crate::history]
+    Subscribe {
+        channel: String,
+        history: Option<usize>,
+        since: Option<u64>,
+    },
+    Unsubscribe {
+        channel: String,
+    },
+    Publish {
+        channel: String,
+        data: String,
+    },
+    /// Send `data` to the connection with the id `to`, whether it is in a channel or not
+    Direct {
+        #[serde(deserialize_with = "connection_id")]
+        to: ConnectionId,
+        data: String,
+    },
+    /// Ask who is in the channel, answered with [Event::Presence]
+    Presence {
+        channel: String,
+    },
+    /// Acknowledge every broadcast of the channel up to `seq`, see [crate::server::ack]
+    Ack {
+        channel: String,
+        seq: u64,
+    },
+}
+
+/// Connection ids go out as numbers, but are taken as strings as well
+fn connection_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ConnectionId, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        Number(ConnectionId),
+        Text(String),
+    }
+
+    match Id::deserialize(deserializer)? {
+        Id::Number(id) => Ok(id),
+        Id::Text(id) => id.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+impl Command {
+    /// Returns [None] when the text is not a command, in which case it is a plain message.
+    /// A JSON object with an `op` is always meant as one, it fails when it is not valid.
+    pub fn parse(text: &str) -> Result<Option<Self>, Error> {
+        let Ok(value) = serde_json::from_str::<Value>(text) else {
+            return Ok(None);
+        };
+        if value.get("op").is_none() {
+            return Ok(None);
+        }
+
+        serde_json::from_value(value)
+            .map(Some)
+            .map_err(|err| Error::BadCommand(err.to_string()))
+    }
+}
+
+/// Events the server sends to a client
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Message {
+        channel: &'a str,
+        /// Unique across every channel
+        id: u64,
+        /// Counts up by one for every message in the channel, so a client can tell when it
+        /// missed one or got one twice. Missing when nobody was in the channel and only
+        /// pattern subscribers got the message.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        /// The connection that published the message
+        sender: ConnectionId,
+        /// When the channel got the message, in milliseconds since the unix epoch
+        timestamp: u64,
+        data: &'a str,
+        /// Only set for binary data, always `base64`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        encoding: Option<&'a str>,
+    },
+    /// A message sent to this connection alone, see [Command::Direct]
+    Direct {
+        id: u64,
+        from: ConnectionId,
+        timestamp: u64,
+        data: &'a str,
+    },
+    /// A command from the client failed, the connection stays open
+    Error { message: &'a str },
+    /// Sent first thing to a connection that can be resumed, see [crate::server::resume]
+    Session {
+        id: ConnectionId,
+        token: &'a str,
+        /// Whether the connection picked up the subscriptions of the one it resumed
+        resumed: bool,
+    },
+    /// The first connection of a user joined the channel
+    PresenceJoin {
+        channel: &'a str,
+        user: &'a str,
+        meta: &'a Value,
+    },
+    /// The last connection of a user left the channel
+    PresenceLeave { channel: &'a str, user: &'a str },
+    /// Every user in the channel, see [Command::Presence]
+    Presence {
+        channel: &'a str,
+        members: &'a [Member],
+    },
+}
+
+impl Event<'_> {
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("events always serialize")
+    }
+}
+
+/// A new message id and the current time in milliseconds since the unix epoch
+fn stamp() -> (u64, u64) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+
+    (NEXT_ID.fetch_add(1, Ordering::Relaxed), timestamp)
+}
+
+/// The [Event::Direct] for a message from `from`
+pub fn direct(from: ConnectionId, data: &str) -> Arc<str> {
+    let (id, timestamp) = stamp();
+    Event::Direct {
+        id,
+        from,
+        timestamp,
+        data,
+    }
+    .encode()
+    .into()
+}
+
+/// The data of a message, published and delivered as a text or a binary frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    Text(Arc<str>),
+    Binary(Arc<[u8]>),
+}
+
+impl From<String> for Payload {
+    fn from(text: String) -> Self {
+        Payload::Text(text.into())
+    }
+}
+
+impl From<&str> for Payload {
+    fn from(text: &str) -> Self {
+        Payload::Text(text.into())
+    }
+}
+
+impl From<Vec<u8>> for Payload {
+    fn from(data: Vec<u8>) -> Self {
+        Payload::Binary(data.into())
+    }
+}
+
+/// A message on its way to its channel, which stamps it into a [Broadcast]
+#[derive(Debug, Clone)]
+pub struct Publication {
+    pub sender: ConnectionId,
+    pub data: Payload,
+}
+
+impl Publication {
+    pub fn new(sender: ConnectionId, data: Payload) -> Self {
+        Self { sender, data }
+    }
+}
+
+/// An [Event::Message] as [Broadcast::parse] reads it back
+#[derive(Deserialize)]
+struct Record {
+    channel: String,
+    id: u64,
+    seq: Option<u64>,
+    sender: ConnectionId,
+    timestamp: u64,
+    data: String,
+    encoding: Option<String>,
+}
+
+/// A message published to a channel, shared by every recipient of the broadcast so
+/// fanning it out only copies a pointer, up to the socket write which copies the frame
+/// for each recipient. Both frames a client may get are encoded at most once per
+/// broadcast, whichever connection needs one first encodes it.
+#[derive(Debug)]
+pub struct Broadcast {
+    channel: String,
+    id: u64,
+    seq: Option<u64>,
+    sender: ConnectionId,
+    timestamp: u64,
+    data: Payload,
+    event: OnceLock<Arc<str>>,
+}
+
+impl Broadcast {
+    /// Stamp the message with a new id and the current time, `seq` is up to the channel
+    pub fn new(channel: &str, seq: Option<u64>, msg: Publication) -> Arc<Self> {
+        let (id, timestamp) = stamp();
+
+        Arc::new(Self {
+            channel: channel.to_string(),
+            id,
+            seq,
+            sender: msg.sender,
+            timestamp,
+            data: msg.data,
+            event: OnceLock::new(),
+        })
+    }
+
+    /// A broadcast the server makes itself, e.g. an [Event::PresenceJoin]. It has no
+    /// sequence number and goes out as `event` to everybody.
+    pub fn notice(channel: &str, event: String) -> Arc<Self> {
+        let (id, timestamp) = stamp();
+        let event: Arc<str> = event.into();
+
+        Arc::new(Self {
+            channel: channel.to_string(),
+            id,
+            seq: None,
+            sender: SERVER,
+            timestamp,
+            data: Payload::Text(event.clone()),
+            event: OnceLock::from(event),
+        })
+    }
+
+    /// Reads back a frame made by [Broadcast::event], [None] when it isn't one. Ids
+    /// handed out from now on are higher than the one read back.
+    pub fn parse(event: &str) -> Option<Arc<Self>> {
+        let record: Record = serde_json::from_str(event).ok()?;
+        let data = match record.encoding.as_deref() {
+            None => Payload::Text(record.data.into()),
+            Some("base64") => Payload::Binary(BASE64.decode(record.data).ok()?.into()),
+            Some(_) => return None,
+        };
+        NEXT_ID.fetch_max(record.id + 1, Ordering::Relaxed);
+
+        Some(Arc::new(Self {
+            channel: record.channel,
+            id: record.id,
+            seq: record.seq,
+            sender: record.sender,
+            timestamp: record.timestamp,
+            data,
+            event: OnceLock::from(Arc::from(event)),
+        }))
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    pub fn seq(&self) -> Option<u64> {
+        self.seq
+    }
+
+    pub fn sender(&self) -> ConnectionId {
+        self.sender
+    }
+
+    /// The data as it was published, binary data goes out as is to sockets that
+    /// connected to the channel itself
+    pub fn data(&self) -> Payload {
+        self.data.clone()
+    }
+
+    /// The frame for everything else, an [Event::Message]
+    pub fn event(&self) -> Arc<str> {
+        self.event
+            .get_or_init(|| {
+                let (data, encoding) = match &self.data {
+                    Payload::Text(text) => (Cow::Borrowed(&**text), None),
+                    Payload::Binary(data) => (Cow::Owned(BASE64.encode(data)), Some("base64")),
+                };
+                Event::Message {
+                    channel: &self.channel,
+                    id: self.id,
+                    seq: self.seq,
+                    sender: self.sender,
+                    timestamp: self.timestamp,
+                    data: &data,
+                    encoding,
+                }
+                .encode()
+                .into()
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn event(msg: &Broadcast) -> Value {
+        serde_json::from_str(&msg.event()).unwrap()
+    }
+
+    #[test]
+    fn broadcast_encodes_the_event_once() {
+        let msg = Broadcast::new("room", Some(3), Publication::new(7, "hello".into()));
+
+        assert_eq!(msg.data(), "hello".into());
+        assert_eq!(
+            event(&msg),
+            json!({
+                "op": "message",
+                "channel": "room",
+                "id": msg.id,
+                "seq": 3,
+                "sender": 7,
+                "timestamp": msg.timestamp,
+                "data": "hello",
+            })
+        );
+        assert!(Arc::ptr_eq(&msg.event(), &msg.event()));
+    }
+
+    #[test]
+    fn binary_events_are_base64_encoded() {
+        let msg = Broadcast::new("room", Some(1), Publication::new(7, vec![0, 1, 255].into()));
+
+        assert_eq!(msg.data(), Payload::Binary(Arc::from([0, 1, 255])));
+        assert_eq!(event(&msg)["data"], "AAH/");
+        assert_eq!(event(&msg)["encoding"], "base64");
+    }
+
+    #[test]
+    fn broadcasts_parse_back_from_their_event() {
+        for data in [Payload::from("hello"), Payload::from(vec![0, 1, 255])] {
+            let msg = Broadcast::new("room", Some(3), Publication::new(7, data.clone()));
+
+            let parsed = Broadcast::parse(&msg.event()).unwrap();
+            assert_eq!(
+                (parsed.id, parsed.seq(), parsed.sender()),
+                (msg.id, Some(3), 7)
+            );
+            assert_eq!(parsed.data(), data);
+            assert!(Broadcast::new("room", None, Publication::new(7, data)).id > msg.id);
+        }
+        assert!(Broadcast::parse(r#"{"op":"presence_leave","channel":"room"}"#).is_none());
+    }
+
+    #[test]
+    fn direct_takes_the_id_as_a_number_or_a_string() {
+        let expected = Some(Command::Direct {
+            to: 7,
+            data: "hi".to_string(),
+        });
+
+        assert_eq!(
+            Command::parse(r#"{"op":"direct","to":7,"data":"hi"}"#).unwrap(),
+            expected
+        );
+        assert_eq!(
+            Command::parse(r#"{"op":"direct","to":"7","data":"hi"}"#).unwrap(),
+            expected
+        );
+        assert!(matches!(
+            Command::parse(r#"{"op":"direct","to":"seven","data":"hi"}"#),
+            Err(Error::BadCommand(_))
+        ));
+    }
+
+    #[test]
+    fn acks_carry_a_seq() {
+        assert_eq!(
+            Command::parse(r#"{"op":"ack","channel":"room","seq":3}"#).unwrap(),
+            Some(Command::Ack {
+                channel: "room".to_string(),
+                seq: 3
+            })
+        );
+        assert!(Command::parse(r#"{"op":"ack","channel":"room"}"#).is_err());
+    }
+
+    #[test]
+    fn only_objects_with_an_op_are_commands() {
+        for text in ["hello", "42", r#"{"channel":"room"}"#, r#"["op"]"#] {
+            assert_eq!(Command::parse(text).unwrap(), None, "{text}");
+        }
+        for text in [r#"{"op":"subscribe"}"#, r#"{"op":"shout","data":"hi"}"#] {
+            assert!(
+                matches!(Command::parse(text), Err(Error::BadCommand(_))),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_broadcast_gets_a_new_id() {
+        let first = Broadcast::new("room", None, Publication::new(7, "a".into()));
+        let second = Broadcast::new("room", None, Publication::new(7, "b".into()));
+
+        assert!(second.id > first.id);
+        assert_eq!(event(&first).get("seq"), None);
+    }
+}
@@ -1,8 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use super::channel;
 use super::connection;
-use ractor::{Actor, ActorId, ActorProcessingErr, ActorRef};
+use crate::error::{Error, Recovery};
+use crate::history::{Replay, Replayed};
+use crate::presence::Change;
+use crate::protocol::{Broadcast, Publication};
+use ractor::{Actor, ActorId, ActorProcessingErr, ActorRef, RpcReplyPort};
 
 pub struct Balancer;
 
@@ -12,23 +19,286 @@ pub enum DownsteamActor {
     Connection(ActorRef<connection::Message>),
 }
 
+impl DownsteamActor {
+    pub fn get_id(&self) -> ActorId {
+        match self {
+            DownsteamActor::Balancer(actor) => actor.get_id(),
+            DownsteamActor::Connection(actor) => actor.get_id(),
+        }
+    }
+
+    /// Forward a broadcast, see [Message::Out] for `skip`
+    pub fn send_out(&self, msg: &Arc<Broadcast>, skip: Option<ActorId>) -> Result<(), Error> {
+        match self {
+            DownsteamActor::Balancer(actor) => {
+                actor.send_message(Message::Out(msg.clone(), skip))?
+            }
+            DownsteamActor::Connection(actor) => {
+                actor.send_message(connection::Message::Out(msg.clone()))?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Forward a broadcast to every attendie but `skip`, dropping the ones that are gone
+pub fn fan_out(
+    attendies: &mut HashMap<ActorId, DownsteamActor>,
+    msg: &Arc<Broadcast>,
+    skip: Option<ActorId>,
+) {
+    for (id, conn) in attendies.clone() {
+        if Some(id) == skip {
+            continue;
+        }
+        if let Err(err) = conn.send_out(msg, skip) {
+            if err.recovery() == Recovery::DropSubscriber {
+                println!("Downstream Closed");
+                attendies.remove(&id);
+            } else {
+                println!("Dropping message for {id}: {err}");
+            }
+        }
+    }
+}
+
 /// This is the types of message [Balancer] supports
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum Message {
     Join(DownsteamActor),
     Leave(DownsteamActor),
-    In(String),
-    Out(String),
+    /// A message published by a connection below us, `skip` is the connection when it
+    /// doesn't want its message back. The connection may wait to be told it was written,
+    /// see [channel::Written].
+    In(Publication, Option<ActorId>, Option<channel::Written>),
+    /// A broadcast for every attendie, the leaf balancer holding `skip` leaves it out
+    Out(Arc<Broadcast>, Option<ActorId>),
+    /// Hand half of our attendies over to a new sibling balancer
+    Split { to: ActorRef<Message> },
+    /// Hand all of our attendies over to a sibling balancer and stop
+    Merge { into: ActorRef<Message> },
+    /// Sent to the parent of `from`, which switches `attendies` over to `to` in between
+    /// two broadcasts so every broadcast reaches them exactly once
+    Handover(Handover),
+    /// Start broadcasting to these attendies, sent by the parent during a [Message::Handover]
+    Adopt(Vec<DownsteamActor>),
+    /// Stop broadcasting to the attendies we handed over, sent by the parent during a
+    /// [Message::Handover]
+    Release,
+    /// Our upstream `from` failed and got restarted as `to`, join it instead
+    Reattach { from: ActorId, to: UpstreamActor },
+    /// A connection below us joined or left, passed up to the channel which keeps the
+    /// members and tells the attendies. Sent after the join or leave itself, so the
+    /// connection hears about its own join but not about its own leave.
+    Presence(Change),
+    /// A connection below us joined and wants to catch up, passed up to the channel
+    /// after the join like [Message::Presence]. Every broadcast the channel sent before
+    /// taking the replay reaches the connection either live or replayed.
+    Replay(Replay, RpcReplyPort<Replayed>),
 }
 
+#[derive(Debug, Clone)]
+pub struct Handover {
+    pub from: ActorRef<Message>,
+    pub to: ActorRef<Message>,
+    pub attendies: Vec<DownsteamActor>,
+    /// `from` is merging into `to` and should no longer get broadcasts
+    pub retire: bool,
+}
+
+impl Handover {
+    /// Carried out by the parent of both balancers, in its own message order
+    pub fn apply(self, attendies: &mut HashMap<ActorId, DownsteamActor>) -> Result<(), Error> {
+        if self.retire {
+            attendies.remove(&self.from.get_id());
+        }
+        self.to.send_message(Message::Adopt(self.attendies))?;
+        self.from.send_message(Message::Release)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum UpstreamActor {
     Balancer(ActorRef<Message>),
     Channel(ActorRef<channel::Message>),
 }
 
+impl UpstreamActor {
+    pub fn get_id(&self) -> ActorId {
+        match self {
+            UpstreamActor::Balancer(actor) => actor.get_id(),
+            UpstreamActor::Channel(actor) => actor.get_id(),
+        }
+    }
+
+    pub fn join(&self, downstream: DownsteamActor) -> Result<(), Error> {
+        match self {
+            UpstreamActor::Balancer(actor) => actor.send_message(Message::Join(downstream))?,
+            UpstreamActor::Channel(actor) => {
+                actor.send_message(channel::Message::Join(downstream))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn leave(&self, downstream: DownsteamActor) -> Result<(), Error> {
+        match self {
+            UpstreamActor::Balancer(actor) => actor.send_message(Message::Leave(downstream))?,
+            UpstreamActor::Channel(actor) => {
+                actor.send_message(channel::Message::Leave(downstream))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn publish(
+        &self,
+        msg: Publication,
+        skip: Option<ActorId>,
+        written: Option<channel::Written>,
+    ) -> Result<(), Error> {
+        match self {
+            UpstreamActor::Balancer(actor) => {
+                actor.send_message(Message::In(msg, skip, written))?
+            }
+            UpstreamActor::Channel(actor) => {
+                actor.send_message(channel::Message::Message(msg, skip, written))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn presence(&self, change: Change) -> Result<(), Error> {
+        match self {
+            UpstreamActor::Balancer(actor) => actor.send_message(Message::Presence(change))?,
+            UpstreamActor::Channel(actor) => {
+                actor.send_message(channel::Message::Presence(change))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn replay(&self, replay: Replay, reply: RpcReplyPort<Replayed>) -> Result<(), Error> {
+        match self {
+            UpstreamActor::Balancer(actor) => actor.send_message(Message::Replay(replay, reply))?,
+            UpstreamActor::Channel(actor) => {
+                actor.send_message(channel::Message::Replay(replay, reply))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handover(&self, handover: Handover) -> Result<(), Error> {
+        match self {
+            UpstreamActor::Balancer(actor) => actor.send_message(Message::Handover(handover))?,
+            UpstreamActor::Channel(actor) => {
+                actor.send_message(channel::Message::Handover(handover))?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The number of attendies a balancer has, reported by the balancer whenever it
+/// changes so a [super::selector::BalancerSelector] can read it without a round trip.
+pub type Load = Arc<AtomicUsize>;
+
+pub struct BalancerArguments {
+    pub upstream: UpstreamActor,
+    pub load: Load,
+}
+
+/// How long a merged balancer keeps forwarding for the attendies it handed over,
+/// until they all got told about their new balancer
+const RETIRE_GRACE: Duration = Duration::from_secs(5);
+
+/// A handover we asked our parent for and that it didn't carry out yet
+struct PendingHandover {
+    to: ActorRef<Message>,
+    attendies: HashSet<ActorId>,
+    /// Handed over attendies that left in the meantime, `to` is told once it adopted them
+    left: Vec<DownsteamActor>,
+    retire: bool,
+}
+
 pub struct BalancerState {
     attendies: HashMap<ActorId, DownsteamActor>,
     upstream: UpstreamActor,
+    load: Load,
+    handover: Option<PendingHandover>,
+    /// Where handed over attendies went, for the ones that still talk to us
+    moved: HashMap<ActorId, ActorRef<Message>>,
+    /// The balancer we merged into
+    retired_into: Option<ActorRef<Message>>,
+}
+
+impl BalancerState {
+    fn report_load(&self) {
+        self.load.store(self.attendies.len(), Ordering::Relaxed);
+    }
+
+    fn start_handover(
+        &mut self,
+        myself: ActorRef<Message>,
+        to: ActorRef<Message>,
+        count: usize,
+        retire: bool,
+    ) -> Result<(), Error> {
+        let attendies: Vec<DownsteamActor> = self.attendies.values().take(count).cloned().collect();
+        self.handover = Some(PendingHandover {
+            to: to.clone(),
+            attendies: attendies.iter().map(DownsteamActor::get_id).collect(),
+            left: Vec::new(),
+            retire,
+        });
+        if retire {
+            self.retired_into = Some(to.clone());
+        }
+
+        // We keep broadcasting to them until our parent releases us
+        self.upstream.handover(Handover {
+            from: myself,
+            to,
+            attendies,
+            retire,
+        })
+    }
+
+    fn release(&mut self, myself: &ActorRef<Message>) -> Result<(), Error> {
+        let Some(handover) = self.handover.take() else {
+            return Ok(());
+        };
+
+        for id in handover.attendies {
+            let Some(conn) = self.attendies.remove(&id) else {
+                continue;
+            };
+            self.moved.insert(id, handover.to.clone());
+            if let DownsteamActor::Connection(conn) = conn {
+                conn.send_message(connection::Message::Migrate {
+                    from: myself.get_id(),
+                    to: UpstreamActor::Balancer(handover.to.clone()),
+                })?;
+            }
+        }
+        for conn in handover.left {
+            handover.to.send_message(Message::Leave(conn))?;
+        }
+        if handover.retire {
+            myself.exit_after(RETIRE_GRACE);
+        }
+
+        Ok(())
+    }
 }
 
 // the implementation of our actor's "logic"
@@ -38,7 +308,7 @@ impl Actor for Balancer {
     // and (optionally) internal state
     type State = BalancerState;
     // Startup initialization args
-    type Arguments = UpstreamActor;
+    type Arguments = BalancerArguments;
 
     // Initially we need to create our state, and potentially
     // start some internal processing (by posting a message for
@@ -46,99 +316,233 @@ impl Actor for Balancer {
     async fn pre_start(
         &self,
         myself: ActorRef<Self::Msg>,
-        upstream: Self::Arguments,
+        BalancerArguments { upstream, load }: Self::Arguments,
     ) -> Result<Self::State, ActorProcessingErr> {
-        match upstream {
-            UpstreamActor::Balancer(ref actor) => {
-                actor
-                    .send_message(Message::Join(DownsteamActor::Balancer(myself)))
-                    .unwrap();
-            }
-            UpstreamActor::Channel(ref actor) => {
-                actor.send_message(channel::Message::Join(myself)).unwrap();
-            }
-        }
+        upstream.join(DownsteamActor::Balancer(myself))?;
 
         // create the initial state
         Ok(BalancerState {
             attendies: HashMap::new(),
             upstream,
+            load,
+            handover: None,
+            moved: HashMap::new(),
+            retired_into: None,
         })
     }
 
+    // Let the upstream stop fanning out to us. The upstream might be stopping
+    // at the same time (e.g. the whole channel is torn down) so a failed send is fine.
+    async fn post_stop(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        let _ = state.upstream.leave(DownsteamActor::Balancer(myself));
+
+        Ok(())
+    }
+
     async fn handle(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
-            Message::Join(conn) => match conn {
-                DownsteamActor::Balancer(conn) => {
-                    state
-                        .attendies
-                        .insert(conn.get_id(), DownsteamActor::Balancer(conn));
+            Message::Join(conn) => match &state.retired_into {
+                // Picked for this connection right before we merged away
+                Some(into) => {
+                    if let DownsteamActor::Connection(conn) = &conn {
+                        conn.send_message(connection::Message::Migrate {
+                            from: myself.get_id(),
+                            to: UpstreamActor::Balancer(into.clone()),
+                        })?;
+                    }
+                    state.moved.insert(conn.get_id(), into.clone());
+                    into.send_message(Message::Join(conn))?;
                 }
-                DownsteamActor::Connection(conn) => {
-                    state
-                        .attendies
-                        .insert(conn.get_id(), DownsteamActor::Connection(conn));
+                None => {
+                    state.attendies.insert(conn.get_id(), conn);
                 }
             },
-            Message::Leave(conn) => match conn {
-                DownsteamActor::Balancer(conn) => {
-                    state.attendies.remove(&conn.get_id());
+            Message::Leave(conn) => {
+                let id = conn.get_id();
+                if let Some(to) = state.moved.remove(&id) {
+                    to.send_message(Message::Leave(conn))?;
+                } else if state.attendies.remove(&id).is_some() {
+                    if let Some(handover) = state
+                        .handover
+                        .as_mut()
+                        .filter(|handover| handover.attendies.contains(&id))
+                    {
+                        handover.left.push(conn);
+                    }
                 }
-                DownsteamActor::Connection(conn) => {
-                    state.attendies.remove(&conn.get_id());
+            }
+            Message::In(msg, skip, written) => {
+                // Fails while our upstream is being restarted, we are restarted along
+                // with the rest of the channel if it can't be
+                state.upstream.publish(msg, skip, written)?;
+            }
+            Message::Out(msg, skip) => {
+                fan_out(&mut state.attendies, &msg, skip);
+            }
+            Message::Presence(change) => {
+                state.upstream.presence(change)?;
+            }
+            Message::Replay(replay, reply) => {
+                state.upstream.replay(replay, reply)?;
+            }
+            Message::Split { to } => {
+                if state.handover.is_some() {
+                    // Try again once the running handover is done
+                    myself.send_message(Message::Split { to })?;
+                } else {
+                    let count = state.attendies.len() / 2;
+                    state.start_handover(myself, to, count, false)?;
                 }
-            },
-            Message::In(msg) => match &state.upstream {
-                UpstreamActor::Balancer(actor) => {
-                    actor.send_message(Message::In(msg)).unwrap();
+            }
+            Message::Merge { into } => {
+                if state.handover.is_some() {
+                    myself.send_message(Message::Merge { into })?;
+                } else {
+                    let count = state.attendies.len();
+                    state.start_handover(myself, into, count, true)?;
                 }
-                UpstreamActor::Channel(actor) => {
-                    actor.send_message(channel::Message::Message(msg)).unwrap();
+            }
+            Message::Handover(handover) => {
+                handover.apply(&mut state.attendies)?;
+            }
+            Message::Adopt(attendies) => {
+                for conn in attendies {
+                    state.attendies.insert(conn.get_id(), conn);
                 }
-            },
-            Message::Out(msg) => {
-                for (id, conn) in state.attendies.clone() {
-                    match conn {
-                        DownsteamActor::Balancer(conn) => {
-                            match conn.send_message(Message::Out(msg.clone())) {
-                                Ok(_) => (),
-                                Err(err) => match err {
-                                    ractor::MessagingErr::SendErr(_)
-                                    | ractor::MessagingErr::ChannelClosed => {
-                                        println!("Balancer Closed");
-                                        state.attendies.remove(&id);
-                                    }
-                                    ractor::MessagingErr::InvalidActorType => {
-                                        println!("Invalid actor type")
-                                    }
-                                },
-                            }
-                        }
-                        DownsteamActor::Connection(conn) => {
-                            match conn.send_message(connection::Message::Out(msg.clone())) {
-                                Ok(_) => (),
-                                Err(err) => match err {
-                                    ractor::MessagingErr::SendErr(_)
-                                    | ractor::MessagingErr::ChannelClosed => {
-                                        println!("Balancer Closed");
-                                        state.attendies.remove(&id);
-                                    }
-                                    ractor::MessagingErr::InvalidActorType => {
-                                        println!("Invalid actor type")
-                                    }
-                                },
-                            }
-                        }
-                    }
+            }
+            Message::Release => {
+                state.release(&myself)?;
+            }
+            Message::Reattach { from, to } => {
+                if state.upstream.get_id() == from {
+                    to.join(DownsteamActor::Balancer(myself))?;
+                    state.upstream = to;
                 }
             }
         }
+        state.report_load();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::History;
+    use crate::presence::Members;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    /// Stands in for a connection, handing whatever it gets to the test
+    struct Probe;
+
+    impl Actor for Probe {
+        type Msg = connection::Message;
+        type State = mpsc::UnboundedSender<connection::Message>;
+        type Arguments = mpsc::UnboundedSender<connection::Message>;
+
+        async fn pre_start(
+            &self,
+            _myself: ActorRef<Self::Msg>,
+            sender: Self::Arguments,
+        ) -> Result<Self::State, ActorProcessingErr> {
+            Ok(sender)
+        }
+
+        async fn handle(
+            &self,
+            _myself: ActorRef<Self::Msg>,
+            message: Self::Msg,
+            sender: &mut Self::State,
+        ) -> Result<(), ActorProcessingErr> {
+            let _ = sender.send(message);
+
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn fan_out_drops_stopped_attendies() {
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let (live, _) = Actor::spawn(None, Probe, sender.clone()).await.unwrap();
+        let (gone, handle) = Actor::spawn(None, Probe, sender).await.unwrap();
+        gone.stop(None);
+        handle.await.unwrap();
+
+        let mut attendies = HashMap::new();
+        for conn in [&live, &gone] {
+            attendies.insert(conn.get_id(), DownsteamActor::Connection(conn.clone()));
+        }
+        let msg = Broadcast::new("room", Some(1), Publication::new(1, "hello".into()));
+        fan_out(&mut attendies, &msg, None);
+
+        assert_eq!(attendies.len(), 1);
+        assert!(attendies.contains_key(&live.get_id()));
+        assert!(matches!(
+            received.recv().await,
+            Some(connection::Message::Out(msg)) if msg.data() == "hello".into()
+        ));
+    }
+
+    #[tokio::test]
+    async fn fan_out_leaves_out_the_sender() {
+        let (sender, mut received) = mpsc::unbounded_channel();
+        let (publisher, _) = Actor::spawn(None, Probe, sender.clone()).await.unwrap();
+        let (sender, mut other_received) = mpsc::unbounded_channel();
+        let (other, _) = Actor::spawn(None, Probe, sender).await.unwrap();
+
+        let mut attendies = HashMap::new();
+        for conn in [&publisher, &other] {
+            attendies.insert(conn.get_id(), DownsteamActor::Connection(conn.clone()));
+        }
+        let msg = Broadcast::new("room", Some(1), Publication::new(1, "hello".into()));
+        fan_out(&mut attendies, &msg, Some(publisher.get_id()));
+
+        assert!(matches!(
+            other_received.recv().await,
+            Some(connection::Message::Out(_))
+        ));
+        assert!(received.try_recv().is_err());
+        assert_eq!(attendies.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn balancer_does_not_start_under_a_stopped_upstream() {
+        let (channel_actor, handle) = Actor::spawn(
+            None,
+            channel::Channel,
+            channel::ChannelArguments {
+                name: "room".to_string(),
+                topics: channel::Topics::default(),
+                members: Arc::new(Mutex::new(Members::new("room"))),
+                history: Arc::new(Mutex::new(History::new(0))),
+            },
+        )
+        .await
+        .unwrap();
+        channel_actor.stop(None);
+        handle.await.unwrap();
+
+        let spawned = Actor::spawn(
+            None,
+            Balancer,
+            BalancerArguments {
+                upstream: UpstreamActor::Channel(channel_actor),
+                load: Load::default(),
+            },
+        )
+        .await;
+
+        assert!(spawned.is_err());
+    }
+}
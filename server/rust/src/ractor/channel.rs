@@ -1,20 +1,87 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 
-use ractor::{Actor, ActorId, ActorProcessingErr, ActorRef};
+use ractor::{Actor, ActorId, ActorProcessingErr, ActorRef, RpcReplyPort};
 use super::balancer;
+use super::connection;
+use crate::error::Error;
+use crate::history::{Appended, History, Replay, Replayed};
+use crate::presence::{Change, Members};
+use crate::protocol::{Broadcast, Publication};
+use crate::topic::TopicIndex;
+
+/// Pattern subscriptions of every channel, written by the registry and read on publish
+pub type Topics = Arc<RwLock<TopicIndex<ActorId, ActorRef<connection::Message>>>>;
 
 pub struct Channel;
 
+/// Told whether a broadcast was written to the channel's journal, see
+/// [crate::history::History::append]
+pub type Written = RpcReplyPort<Result<(), Error>>;
+
 /// This is the types of message [PingPong] supports
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Message {
-    Join(ActorRef<balancer::Message>),
-    Leave(ActorRef<balancer::Message>),
-    Message(String),
+    Join(balancer::DownsteamActor),
+    Leave(balancer::DownsteamActor),
+    /// See [balancer::Message::In] for the last two fields
+    Message(Publication, Option<ActorId>, Option<Written>),
+    /// Broadcasts the journal wrote meanwhile, oldest first
+    Released(Vec<Arc<Broadcast>>),
+    /// See [balancer::Message::Handover]
+    Handover(balancer::Handover),
+    /// See [balancer::Message::Presence]
+    Presence(Change),
+    /// See [balancer::Message::Replay]
+    Replay(Replay, RpcReplyPort<Replayed>),
+}
+
+pub struct ChannelArguments {
+    pub name: String,
+    pub topics: Topics,
+    /// Kept by the registry, it answers presence queries from it
+    pub members: Arc<Mutex<Members>>,
+    /// Kept by the registry as well, so the channel carries on with its sequence numbers
+    /// when it is restarted
+    pub history: Arc<Mutex<History>>,
 }
 
 pub struct ChannelState {
-    balancers: HashMap<ActorId, ActorRef<balancer::Message>>,
+    name: String,
+    /// The first layer of balancers, or the connections themselves when the
+    /// channel has no balancer tree in front of it
+    attendies: HashMap<ActorId, balancer::DownsteamActor>,
+    topics: Topics,
+    members: Arc<Mutex<Members>>,
+    history: Arc<Mutex<History>>,
+}
+
+impl ChannelState {
+    /// Hand the broadcast to every attendie and every connection subscribed to a
+    /// pattern it matches
+    fn fan_out(&mut self, msg: &Arc<Broadcast>, skip: Option<ActorId>) {
+        balancer::fan_out(&mut self.attendies, msg, skip);
+
+        // Connections subscribed through a pattern are reached directly, a connection
+        // matching several patterns only shows up once
+        let matched = self
+            .topics
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .matches(&self.name);
+        for (id, conn) in matched {
+            if Some(id) == skip {
+                continue;
+            }
+            if conn
+                .send_message(connection::Message::Match(msg.clone()))
+                .is_err()
+            {
+                println!("Connection Closed");
+            }
+        }
+    }
 }
 
 // the implementation of our actor's "logic"
@@ -24,7 +91,7 @@ impl Actor for Channel {
     // and (optionally) internal state
     type State = ChannelState;
     // Startup initialization args
-    type Arguments = ();
+    type Arguments = ChannelArguments;
 
     // Initially we need to create our state, and potentially
     // start some internal processing (by posting a message for
@@ -32,46 +99,109 @@ impl Actor for Channel {
     async fn pre_start(
         &self,
         _myself: ActorRef<Self::Msg>,
-        _: (),
+        args: ChannelArguments,
     ) -> Result<Self::State, ActorProcessingErr> {
         // create the initial state
         Ok(ChannelState {
-            balancers: HashMap::new(),
+            name: args.name,
+            attendies: HashMap::new(),
+            topics: args.topics,
+            members: args.members,
+            history: args.history,
         })
     }
 
     async fn handle(
         &self,
-        _myself: ActorRef<Self::Msg>,
+        myself: ActorRef<Self::Msg>,
         message: Self::Msg,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
         match message {
             Message::Join(conn) => {
-                state.balancers.insert(conn.get_id(), conn);
+                state.attendies.insert(conn.get_id(), conn);
             }
             Message::Leave(conn) => {
-                state.balancers.remove(&conn.get_id());
+                state.attendies.remove(&conn.get_id());
             }
-            Message::Message(msg) => {
-                for (id, conn) in state.balancers.clone() {
-                    match conn.send_message(balancer::Message::Out(msg.clone())) {
-                        Ok(_) => (),
-                        Err(err) => match err {
-                            ractor::MessagingErr::SendErr(_)
-                            | ractor::MessagingErr::ChannelClosed => {
-                                println!("Channel Closed");
-                                state.balancers.remove(&id);
-                            }
-                            ractor::MessagingErr::InvalidActorType => {
-                                println!("Invalid actor type")
-                            }
-                        },
+            Message::Handover(handover) => {
+                handover.apply(&mut state.attendies)?;
+            }
+            Message::Replay(replay, reply) => {
+                let history = state.history.lock().unwrap_or_else(PoisonError::into_inner);
+                let replayed = Replayed {
+                    epoch: history.epoch(),
+                    seq: history.seq(),
+                    broadcasts: history.replay(replay),
+                };
+                drop(history);
+                // The connection stopped waiting when the reply can't be sent
+                let _ = reply.send(replayed);
+            }
+            Message::Presence(change) => {
+                let notice = state
+                    .members
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .apply(&change);
+                if let Some(notice) = notice {
+                    balancer::fan_out(&mut state.attendies, &notice, None);
+                }
+            }
+            Message::Message(msg, skip, written) => {
+                // Every broadcast goes through here, so their order is the one we stamp
+                let mut history = state.history.lock().unwrap_or_else(PoisonError::into_inner);
+                let appending = history.append(&state.name, msg);
+                match appending.try_written() {
+                    Ok(appended) => {
+                        let released = history.release(&appended);
+                        drop(history);
+                        for msg in released {
+                            state.fan_out(&msg, skip);
+                        }
+                        if let Some(written) = written {
+                            let _ = written.send(appended.result);
+                        }
+                    }
+                    Err(appending) => {
+                        drop(history);
+                        let history = state.history.clone();
+                        tokio::spawn(async move {
+                            let appended = appending.written().await;
+                            release(&history, &myself, appended, written);
+                        });
                     }
                 }
             }
+            Message::Released(released) => {
+                // Connections leave out their own broadcasts themselves
+                for msg in released {
+                    state.fan_out(&msg, None);
+                }
+            }
         }
 
         Ok(())
     }
 }
+
+/// Release a broadcast the journal is done with, and hand whatever that releases to the
+/// channel under the history's lock so it gets them in order. A channel restarted in
+/// the meantime misses them like every other message sent to it while it was down.
+fn release(
+    history: &Mutex<History>,
+    channel: &ActorRef<Message>,
+    appended: Appended,
+    written: Option<Written>,
+) {
+    let mut history = history.lock().unwrap_or_else(PoisonError::into_inner);
+    let released = history.release(&appended);
+    if !released.is_empty() && channel.send_message(Message::Released(released)).is_err() {
+        println!("Dropping messages for a stopped channel");
+    }
+    drop(history);
+
+    if let Some(written) = written {
+        let _ = written.send(appended.result);
+    }
+}
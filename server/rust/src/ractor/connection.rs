@@ -1,23 +1,268 @@
-use axum::extract::ws::{self, WebSocket};
-use futures::SinkExt;
-use futures_util::stream::SplitSink;
-use ractor::{Actor, ActorProcessingErr, ActorRef};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use ractor::{call, Actor, ActorId, ActorProcessingErr, ActorRef, RpcReplyPort};
 
 use super::balancer;
+use super::channel;
+use super::registry;
+use crate::error::Error;
+use crate::history::{Replay, Replayed};
+use crate::presence::Change;
+use crate::protocol;
+use crate::server::session;
+use crate::server::Subscriber;
+use crate::topic;
 
+/// Stands in for a connection in the balancer tree, it keeps track of the balancers
+/// the connection is attached to and hands broadcasts to its session
 pub struct Connection;
 
-/// This is the types of message [PingPong] supports
-#[derive(Debug, Clone)]
+/// This is the types of message [Connection] supports
+#[derive(Debug)]
 pub enum Message {
-    In(String),
-    Out(String),
+    /// Join a channel, or subscribe to a pattern, and reply once we are in with what
+    /// the replay asked for, see [balancer::Message::Replay]
+    Subscribe(
+        String,
+        Option<Replay>,
+        RpcReplyPort<Result<Replayed, Error>>,
+    ),
+    Unsubscribe(String),
+    /// Publish to the channel, see [channel::Written] for the last field
+    Publish {
+        channel: String,
+        msg: protocol::Payload,
+        written: Option<channel::Written>,
+    },
+    Out(Arc<protocol::Broadcast>),
+    /// A message from a channel matching one of our pattern subscriptions
+    Match(Arc<protocol::Broadcast>),
+    /// The balancer we were attached to handed us over to another one
+    Migrate {
+        from: ActorId,
+        to: balancer::UpstreamActor,
+    },
+    /// The balancer (or channel) `from` failed and got restarted as `to`, join it instead
+    Reattach {
+        from: ActorId,
+        to: balancer::UpstreamActor,
+    },
+    /// The channel kept failing and was given up on, close the socket
+    Evict(String),
     Close,
 }
 
+pub struct ConnectionArguments {
+    pub subscriber: Subscriber,
+    pub registry_actor: ActorRef<registry::Message>,
+}
+
 pub struct ConnectionState {
-    pub ws: SplitSink<WebSocket, ws::Message>,
-    pub balancer_actor: ActorRef<balancer::Message>,
+    pub subscriber: Subscriber,
+    pub registry_actor: ActorRef<registry::Message>,
+    /// The balancer (or channel) we are attached to for every channel we are subscribed to
+    pub memberships: HashMap<String, balancer::UpstreamActor>,
+    pub patterns: HashSet<String>,
+    /// The last sequence number a replay covered for every channel we just joined,
+    /// broadcasts up to it that come in live went out with the replay already
+    pub replayed: HashMap<String, u64>,
+}
+
+impl ConnectionState {
+    async fn subscribe(
+        &mut self,
+        myself: ActorRef<Message>,
+        channel: String,
+        replay: Option<Replay>,
+    ) -> Result<Replayed, Error> {
+        if topic::is_pattern(&channel) {
+            if self.patterns.insert(channel.clone()) {
+                self.registry_actor
+                    .send_message(registry::Message::SubscribePattern(channel, myself))?;
+            }
+            return Ok(Replayed::default());
+        }
+        if self.memberships.contains_key(&channel) {
+            return Ok(Replayed::default());
+        }
+
+        let upstream = call!(
+            self.registry_actor,
+            registry::Message::Join,
+            channel.clone(),
+            myself.clone()
+        )
+        .map_err(|_| Error::ChannelUnavailable(channel.clone()))?;
+        if let Err(err) = upstream.join(balancer::DownsteamActor::Connection(myself.clone())) {
+            // Give the seat back
+            self.registry_actor
+                .send_message(registry::Message::Leave(channel, myself.get_id()))?;
+            return Err(err);
+        }
+        if let Some(identity) = self.subscriber.identity() {
+            let change = Change::Join(self.subscriber.get_id(), identity.clone());
+            if let Err(err) = upstream.presence(change) {
+                println!("Failed to announce joining {channel}: {err}");
+            }
+        }
+        self.memberships.insert(channel.clone(), upstream.clone());
+
+        // Asked for even without a replay, it tells the sequence number the channel is at.
+        // Broadcasts coming in live wait in our mailbox until we got it.
+        let replay = replay.unwrap_or(Replay::Last(0));
+        let (reply, replayed) = ractor::concurrency::oneshot();
+        let replayed = match upstream.replay(replay, reply.into()) {
+            Ok(()) => replayed.await.ok(),
+            Err(_) => None,
+        };
+        let Some(replayed) = replayed else {
+            // The channel is being restarted, the client only joins along with its replay
+            self.unsubscribe(myself, channel.clone())?;
+            return Err(Error::ChannelUnavailable(channel));
+        };
+        self.replayed.insert(channel, replayed.seq);
+
+        Ok(replayed)
+    }
+
+    /// Whether the broadcast already went out with the replay of its channel
+    fn was_replayed(&mut self, msg: &protocol::Broadcast) -> bool {
+        let (Some(replayed), Some(seq)) = (self.replayed.get(msg.channel()), msg.seq()) else {
+            return false;
+        };
+        if seq <= *replayed {
+            return true;
+        }
+        // Everything from here on is new
+        self.replayed.remove(msg.channel());
+        false
+    }
+
+    fn unsubscribe(&mut self, myself: ActorRef<Message>, channel: String) -> Result<(), Error> {
+        if self.patterns.remove(&channel) {
+            self.registry_actor
+                .send_message(registry::Message::UnsubscribePattern(channel, myself.get_id()))?;
+            return Ok(());
+        }
+        let Some(upstream) = self.memberships.remove(&channel) else {
+            return Ok(());
+        };
+        self.replayed.remove(&channel);
+
+        // Hand the seat back even when the upstream is gone
+        let left = upstream.leave(balancer::DownsteamActor::Connection(myself.clone()));
+        if let Some(identity) = self.subscriber.identity() {
+            let change = Change::Leave(self.subscriber.get_id(), identity.clone());
+            if let Err(err) = upstream.presence(change) {
+                println!("Failed to announce leaving {channel}: {err}");
+            }
+        }
+        self.registry_actor
+            .send_message(registry::Message::Leave(channel, myself.get_id()))?;
+
+        left
+    }
+
+    /// Unsubscribe from every channel and pattern, one that fails doesn't keep us in the
+    /// others
+    fn close(&mut self, myself: ActorRef<Message>) {
+        let channels: Vec<String> = self
+            .memberships
+            .keys()
+            .chain(self.patterns.iter())
+            .cloned()
+            .collect();
+        for channel in channels {
+            if let Err(err) = self.unsubscribe(myself.clone(), channel.clone()) {
+                println!("Failed to leave {channel}: {err}");
+            }
+        }
+    }
+
+    fn publish(
+        &self,
+        myself: ActorRef<Message>,
+        channel: String,
+        msg: protocol::Payload,
+        written: Option<channel::Written>,
+    ) -> Result<(), Error> {
+        let msg = protocol::Publication::new(self.subscriber.get_id(), msg);
+        // Left out by whichever actor fans out to us
+        let skip = (!self.subscriber.echo()).then(|| myself.get_id());
+        match self.memberships.get(&channel) {
+            Some(upstream) => upstream.publish(msg, skip, written)?,
+            None => self
+                .registry_actor
+                .send_message(registry::Message::Publish(channel, msg, skip, written))?,
+        }
+
+        Ok(())
+    }
+
+    async fn handle_message(
+        &mut self,
+        myself: ActorRef<Message>,
+        message: Message,
+    ) -> Result<(), Error> {
+        match message {
+            Message::Subscribe(channel, replay, reply) => {
+                let joined = self.subscribe(myself, channel, replay).await;
+                // The session stopped waiting when the reply can't be sent
+                let _ = reply.send(joined);
+            }
+            Message::Unsubscribe(channel) => {
+                self.unsubscribe(myself, channel)?;
+            }
+            Message::Publish {
+                channel,
+                msg,
+                written,
+            } => {
+                self.publish(myself, channel, msg, written)?;
+            }
+            // A channel with a journal can't leave out our own broadcasts, see
+            // [channel::Message::Released]
+            Message::Out(msg) => {
+                if !self.was_replayed(&msg) && self.subscriber.wants(&msg) {
+                    self.subscriber.send_message(session::Message::Out(msg))?;
+                }
+            }
+            Message::Match(msg) => {
+                if self.subscriber.wants(&msg) {
+                    self.subscriber.send_message(session::Message::Match(msg))?;
+                }
+            }
+            Message::Migrate { from, to } => {
+                for upstream in self.memberships.values_mut() {
+                    if upstream.get_id() == from {
+                        *upstream = to.clone();
+                    }
+                }
+            }
+            Message::Reattach { from, to } => {
+                for upstream in self.memberships.values_mut() {
+                    if upstream.get_id() == from {
+                        to.join(balancer::DownsteamActor::Connection(myself.clone()))?;
+                        *upstream = to.clone();
+                    }
+                }
+            }
+            Message::Evict(channel) => {
+                // The registry already dropped the channel and our seat in it
+                self.memberships.remove(&channel);
+                self.replayed.remove(&channel);
+                self.subscriber
+                    .send_message(session::Message::Close(Error::ChannelFailed(channel)))?;
+            }
+            Message::Close => {
+                self.close(myself.clone());
+                myself.stop(None);
+            }
+        };
+
+        Ok(())
+    }
 }
 
 // the implementation of our actor's "logic"
@@ -27,22 +272,23 @@ impl Actor for Connection {
     // and (optionally) internal state
     type State = ConnectionState;
     // Startup initialization args
-    type Arguments = ConnectionState;
+    type Arguments = ConnectionArguments;
 
     // Initially we need to create our state, and potentially
     // start some internal processing (by posting a message for
     // example)
     async fn pre_start(
         &self,
-        myself: ActorRef<Self::Msg>,
-        state: ConnectionState,
+        _myself: ActorRef<Self::Msg>,
+        args: ConnectionArguments,
     ) -> Result<Self::State, ActorProcessingErr> {
-        state
-            .balancer_actor
-            .send_message(balancer::Message::Join(balancer::DownsteamActor::Connection(myself)))
-            .unwrap();
-
-        Ok(state)
+        Ok(ConnectionState {
+            subscriber: args.subscriber,
+            registry_actor: args.registry_actor,
+            memberships: HashMap::new(),
+            patterns: HashSet::new(),
+            replayed: HashMap::new(),
+        })
     }
 
     // This is our main message handler
@@ -52,23 +298,10 @@ impl Actor for Connection {
         message: Self::Msg,
         state: &mut Self::State,
     ) -> Result<(), ActorProcessingErr> {
-        match message {
-            Message::In(msg) => {
-                state
-                    .balancer_actor
-                    .send_message(balancer::Message::In(msg))
-                    .unwrap();
-            }
-            Message::Out(msg) => {
-                state.ws.send(ws::Message::Text(msg)).await.unwrap();
-            }
-            Message::Close => {
-                state
-                    .balancer_actor
-                    .send_message(balancer::Message::Leave(balancer::DownsteamActor::Connection(myself)))
-                    .unwrap();
-            }
-        };
+        // The session deals with what it sees, everything else can only be dropped
+        if let Err(err) = state.handle_message(myself, message).await {
+            println!("Dropping message: {err}");
+        }
 
         Ok(())
     }
@@ -0,0 +1,670 @@
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use super::balancer;
+use super::channel;
+use super::connection;
+use super::selector::{BalancerSelector, Strategy};
+use super::topology::{self, Autoscale, Topology};
+use crate::error::{Error, Recovery};
+use crate::history::{self, History};
+use crate::presence::{Member, Members};
+use crate::protocol::{Broadcast, Publication};
+use crate::topic::TopicIndex;
+use ractor::{
+    Actor, ActorCell, ActorId, ActorProcessingErr, ActorRef, RpcReplyPort, SupervisionEvent,
+};
+
+pub struct Registry;
+
+/// This is the types of message [Registry] supports
+#[derive(Debug)]
+pub enum Message {
+    /// Reserve a seat in the named channel, spawning it if needed, and reply
+    /// with the balancer (or channel) the connection should attach to.
+    Join(
+        String,
+        ActorRef<connection::Message>,
+        RpcReplyPort<balancer::UpstreamActor>,
+    ),
+    /// The history of a channel somebody is waiting to join, opened off the registry as
+    /// it waits for the journal, see [crate::journal]
+    Opened(String, Result<History, Error>),
+    /// Give a seat in the named channel back, stopping it when it was the last one.
+    Leave(String, ActorId),
+    /// Publish to the named channel without holding a seat in it, only pattern
+    /// subscribers see it when nobody is in the channel. See [balancer::Message::In] for
+    /// the last two fields.
+    Publish(
+        String,
+        Publication,
+        Option<ActorId>,
+        Option<channel::Written>,
+    ),
+    /// Who is in the named channel, nobody when it isn't running
+    Presence(String, RpcReplyPort<Vec<Member>>),
+    /// Deliver every channel whose name matches the pattern to the connection
+    SubscribePattern(String, ActorRef<connection::Message>),
+    UnsubscribePattern(String, ActorId),
+    /// Split overloaded leaf balancers and merge under-used ones
+    Rebalance,
+    /// Stop every channel in order, reply once they are gone and stop the registry
+    Stop(RpcReplyPort<()>),
+}
+
+/// How often starting a channel is attempted before its connections are turned away
+const SPAWN_ATTEMPTS: usize = 3;
+
+/// A channel failing more often than this within [RESTART_WINDOW] is given up on
+const MAX_RESTARTS: usize = 5;
+const RESTART_WINDOW: Duration = Duration::from_secs(10);
+
+/// A running channel together with the balancer tree in front of it
+struct ChannelEntry {
+    channel: ActorRef<channel::Message>,
+    balancers: Vec<topology::Node>,
+    leaves: Vec<topology::Leaf>,
+    selector: Box<dyn BalancerSelector>,
+    /// Every connection holding a seat
+    attendies: HashMap<ActorId, ActorRef<connection::Message>>,
+    /// When the channel or one of its balancers failed recently
+    restarts: Vec<Instant>,
+    /// See [channel::ChannelArguments::members]
+    members: Arc<Mutex<Members>>,
+    /// See [channel::ChannelArguments::history]
+    history: Arc<Mutex<History>>,
+}
+
+impl ChannelEntry {
+    fn owns(&self, id: ActorId) -> bool {
+        self.channel.get_id() == id
+            || self.balancers.iter().any(|node| node.balancer.get_id() == id)
+    }
+
+    fn stop(&self) {
+        for node in &self.balancers {
+            node.balancer.stop(None);
+        }
+        self.channel.stop(None);
+    }
+
+    /// Stop the balancers from the leaves up and then the channel, each one once
+    /// everything below it is gone
+    async fn shutdown(self) {
+        for node in self.balancers.iter().rev() {
+            let _ = node.balancer.stop_and_wait(None, None).await;
+        }
+        let _ = self.channel.stop_and_wait(None, None).await;
+    }
+
+    /// Replace the failed channel or balancer with a fresh one in the same spot
+    async fn restart(
+        &mut self,
+        name: &str,
+        failed: ActorId,
+        topics: channel::Topics,
+        supervisor: ActorCell,
+    ) -> Result<(), Error> {
+        if self.channel.get_id() == failed {
+            let channel_actor = spawn_channel_actor(
+                name,
+                topics,
+                self.members.clone(),
+                self.history.clone(),
+                supervisor,
+            )
+            .await?;
+            println!("Channel {name} restarted");
+            self.channel = channel_actor.clone();
+            self.reattach(failed, balancer::UpstreamActor::Channel(channel_actor), None);
+
+            return Ok(());
+        }
+
+        let Some(index) = self
+            .balancers
+            .iter()
+            .position(|node| node.balancer.get_id() == failed)
+        else {
+            return Ok(());
+        };
+        let parent = self.balancers[index].parent.clone();
+        // A failed balancer doesn't get to leave its parent by itself, the parent may
+        // have failed as well
+        let _ = parent.leave(balancer::DownsteamActor::Balancer(
+            self.balancers[index].balancer.clone(),
+        ));
+
+        let (node, leaf) = topology::spawn_balancer(&parent, supervisor).await?;
+        println!("Balancer {failed} restarted as {}", node.balancer.get_id());
+        self.balancers[index] = node;
+        self.reattach(failed, leaf.upstream, Some(leaf.load));
+
+        Ok(())
+    }
+
+    /// Point everything that was attached to `from` at its replacement
+    fn reattach(
+        &mut self,
+        from: ActorId,
+        to: balancer::UpstreamActor,
+        load: Option<balancer::Load>,
+    ) {
+        for node in &mut self.balancers {
+            if node.parent.get_id() == from {
+                node.parent = to.clone();
+                // A child that failed as well gets restarted on its own
+                let _ = node.balancer.send_message(balancer::Message::Reattach {
+                    from,
+                    to: to.clone(),
+                });
+            }
+        }
+
+        let mut orphaned = false;
+        for leaf in &mut self.leaves {
+            if leaf.parent.as_ref().map(|parent| parent.get_id()) == Some(from) {
+                leaf.parent = Some(to.clone());
+            }
+            if leaf.upstream.get_id() == from {
+                leaf.upstream = to.clone();
+                if let Some(load) = &load {
+                    leaf.load = load.clone();
+                }
+                orphaned = true;
+            }
+        }
+
+        // Only the connections that were attached to `from` act on it
+        if orphaned {
+            for conn in self.attendies.values() {
+                if conn
+                    .send_message(connection::Message::Reattach {
+                        from,
+                        to: to.clone(),
+                    })
+                    .is_err()
+                {
+                    println!("Connection Closed");
+                }
+            }
+        }
+    }
+
+    fn select_upstream(&mut self) -> Option<balancer::UpstreamActor> {
+        let leaves = &self.leaves;
+        let index = self
+            .selector
+            .select(leaves.len(), &|index| leaves[index].load.load(Ordering::Relaxed))?;
+        let leaf = &leaves[index];
+        // Count the connection right away, the balancer reports the real number once it
+        // handled the join but a burst of joins shouldn't all see the same stale load
+        leaf.load.fetch_add(1, Ordering::Relaxed);
+
+        Some(leaf.upstream.clone())
+    }
+
+    async fn rebalance(
+        &mut self,
+        autoscale: &Autoscale,
+        supervisor: &ActorCell,
+    ) -> Result<(), Error> {
+        // A balancer takes part in at most one split or merge per round
+        let mut busy: HashSet<ActorId> = HashSet::new();
+
+        let mut splits = Vec::new();
+        for leaf in &self.leaves {
+            let (Some(parent), balancer::UpstreamActor::Balancer(from)) =
+                (&leaf.parent, &leaf.upstream)
+            else {
+                continue;
+            };
+            if leaf.load.load(Ordering::Relaxed) > autoscale.split_above {
+                splits.push((parent.clone(), from.clone()));
+            }
+        }
+        for (parent, from) in splits {
+            let (node, leaf) = topology::spawn_balancer(&parent, supervisor.clone()).await?;
+            let to = node.balancer.clone();
+            println!("Splitting balancer {} into {}", from.get_id(), to.get_id());
+            from.send_message(balancer::Message::Split { to: to.clone() })?;
+            busy.insert(from.get_id());
+            busy.insert(to.get_id());
+            self.balancers.push(node);
+            self.leaves.push(leaf);
+        }
+
+        let mut index = 0;
+        while index < self.leaves.len() {
+            let leaf = &self.leaves[index];
+            let load = leaf.load.load(Ordering::Relaxed);
+            let (Some(parent), balancer::UpstreamActor::Balancer(from)) =
+                (&leaf.parent, &leaf.upstream)
+            else {
+                index += 1;
+                continue;
+            };
+            if load >= autoscale.merge_below || busy.contains(&from.get_id()) {
+                index += 1;
+                continue;
+            }
+
+            // The least loaded sibling with room for our attendies
+            let into = self
+                .leaves
+                .iter()
+                .filter_map(|sibling| match (&sibling.parent, &sibling.upstream) {
+                    (Some(sibling_parent), balancer::UpstreamActor::Balancer(sibling))
+                        if sibling_parent.get_id() == parent.get_id()
+                            && sibling.get_id() != from.get_id()
+                            && !busy.contains(&sibling.get_id()) =>
+                    {
+                        Some((sibling, sibling_load(&self.leaves, sibling.get_id())))
+                    }
+                    _ => None,
+                })
+                .filter(|(_, sibling_load)| sibling_load + load <= autoscale.split_above)
+                .min_by_key(|(_, sibling_load)| *sibling_load)
+                .map(|(sibling, _)| sibling.clone());
+            let Some(into) = into else {
+                index += 1;
+                continue;
+            };
+
+            // No new connections for it, it stops by itself once merged
+            let from = from.clone();
+            self.leaves.swap_remove(index);
+            self.balancers
+                .retain(|node| node.balancer.get_id() != from.get_id());
+            println!("Merging balancer {} into {}", from.get_id(), into.get_id());
+            from.send_message(balancer::Message::Merge { into: into.clone() })?;
+            busy.insert(from.get_id());
+            busy.insert(into.get_id());
+        }
+
+        Ok(())
+    }
+}
+
+fn sibling_load(leaves: &[topology::Leaf], id: ActorId) -> usize {
+    leaves
+        .iter()
+        .find(|leaf| leaf.upstream.get_id() == id)
+        .map_or(0, |leaf| leaf.load.load(Ordering::Relaxed))
+}
+
+pub struct RegistryArguments {
+    pub selector: Strategy,
+    pub topology: Topology,
+    /// Grow and shrink every channel's balancer tree with its attendies, off when [None]
+    pub autoscale: Option<Autoscale>,
+    pub history: history::Config,
+}
+
+/// A connection waiting for its seat, see [Message::Join]
+type Waiting = (
+    ActorRef<connection::Message>,
+    RpcReplyPort<balancer::UpstreamActor>,
+);
+
+pub struct RegistryState {
+    channels: HashMap<String, ChannelEntry>,
+    /// Who is waiting for every channel whose history is being opened
+    opening: HashMap<String, Vec<Waiting>>,
+    topics: channel::Topics,
+    selector: Strategy,
+    topology: Topology,
+    autoscale: Option<Autoscale>,
+    history: history::Config,
+}
+
+async fn spawn_channel_actor(
+    name: &str,
+    topics: channel::Topics,
+    members: Arc<Mutex<Members>>,
+    history: Arc<Mutex<History>>,
+    supervisor: ActorCell,
+) -> Result<ActorRef<channel::Message>, Error> {
+    let (channel_actor, _handle) = Actor::spawn_linked(
+        None,
+        channel::Channel,
+        channel::ChannelArguments {
+            name: name.to_string(),
+            topics,
+            members,
+            history,
+        },
+        supervisor,
+    )
+    .await?;
+
+    Ok(channel_actor)
+}
+
+/// Spawns the channel and its balancer tree, all supervised by the registry
+async fn spawn_channel(
+    name: &str,
+    topics: channel::Topics,
+    selector: Strategy,
+    topology: &Topology,
+    history: History,
+    supervisor: ActorCell,
+) -> Result<ChannelEntry, Error> {
+    let history = Arc::new(Mutex::new(history));
+    let mut attempt = 1;
+    loop {
+        let spawned = try_spawn_channel(
+            name,
+            topics.clone(),
+            selector,
+            topology,
+            &history,
+            &supervisor,
+        );
+        match spawned.await {
+            Err(err) if err.recovery() == Recovery::Retry && attempt < SPAWN_ATTEMPTS => {
+                println!("Retrying to start channel {name}: {err}");
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn try_spawn_channel(
+    name: &str,
+    topics: channel::Topics,
+    selector: Strategy,
+    topology: &Topology,
+    history: &Arc<Mutex<History>>,
+    supervisor: &ActorCell,
+) -> Result<ChannelEntry, Error> {
+    let members = Arc::new(Mutex::new(Members::new(name)));
+    let history = history.clone();
+    let channel_actor = spawn_channel_actor(
+        name,
+        topics,
+        members.clone(),
+        history.clone(),
+        supervisor.clone(),
+    )
+    .await?;
+    let tree = match topology::spawn_tree(&channel_actor, topology, supervisor).await {
+        Ok(tree) => tree,
+        Err(err) => {
+            channel_actor.stop(None);
+            return Err(err);
+        }
+    };
+
+    println!(
+        "Channel {name} started with {} balancers",
+        tree.balancers.len()
+    );
+
+    Ok(ChannelEntry {
+        channel: channel_actor,
+        balancers: tree.balancers,
+        leaves: tree.leaves,
+        selector: selector.build(),
+        attendies: HashMap::new(),
+        restarts: Vec::new(),
+        members,
+        history,
+    })
+}
+
+impl RegistryState {
+    /// Give the connection a seat in the running channel
+    fn seat(
+        &mut self,
+        name: &str,
+        conn: ActorRef<connection::Message>,
+        reply: RpcReplyPort<balancer::UpstreamActor>,
+    ) {
+        let Some(entry) = self.channels.get_mut(name) else {
+            return;
+        };
+        if let Some(upstream) = entry.select_upstream() {
+            let id = conn.get_id();
+            entry.attendies.insert(id, conn);
+            if reply.send(upstream).is_err() {
+                // The caller went away before getting its seat, hand it back
+                self.leave(name, id);
+            }
+        }
+    }
+
+    fn leave(&mut self, name: &str, id: ActorId) {
+        let Some(entry) = self.channels.get_mut(name) else {
+            return;
+        };
+        entry.attendies.remove(&id);
+        if entry.attendies.is_empty() {
+            entry.stop();
+            self.channels.remove(name);
+            println!("Channel {name} stopped");
+        }
+    }
+
+    /// Restart the failed actor in whichever channel it belongs to, evicting every
+    /// connection from the channel when it keeps failing or can't be restarted
+    async fn restart(&mut self, failed: ActorId, supervisor: ActorCell) {
+        let Some((name, entry)) = self
+            .channels
+            .iter_mut()
+            .find(|(_, entry)| entry.owns(failed))
+        else {
+            // Stopped along with its channel, or retired after a merge
+            return;
+        };
+        let name = name.clone();
+
+        let now = Instant::now();
+        entry
+            .restarts
+            .retain(|restart| now.duration_since(*restart) < RESTART_WINDOW);
+        if entry.restarts.len() >= MAX_RESTARTS {
+            println!("Channel {name} keeps failing, giving up on it");
+            self.evict(&name);
+            return;
+        }
+        entry.restarts.push(now);
+
+        if let Err(err) = entry
+            .restart(&name, failed, self.topics.clone(), supervisor)
+            .await
+        {
+            println!("Failed to restart channel {name}: {err}");
+            self.evict(&name);
+        }
+    }
+
+    fn evict(&mut self, name: &str) {
+        let Some(entry) = self.channels.remove(name) else {
+            return;
+        };
+        entry.stop();
+        for conn in entry.attendies.into_values() {
+            let _ = conn.send_message(connection::Message::Evict(name.to_string()));
+        }
+    }
+}
+
+impl Actor for Registry {
+    type Msg = Message;
+    type State = RegistryState;
+    type Arguments = RegistryArguments;
+
+    async fn pre_start(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        args: RegistryArguments,
+    ) -> Result<Self::State, ActorProcessingErr> {
+        if let Some(autoscale) = &args.autoscale {
+            myself.send_interval(autoscale.interval, || Message::Rebalance);
+        }
+
+        Ok(RegistryState {
+            channels: HashMap::new(),
+            opening: HashMap::new(),
+            topics: Arc::new(RwLock::new(TopicIndex::new())),
+            selector: args.selector,
+            topology: args.topology,
+            autoscale: args.autoscale,
+            history: args.history,
+        })
+    }
+
+    async fn handle(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: Self::Msg,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        match message {
+            Message::Join(name, conn, reply) => {
+                if state.channels.contains_key(&name) {
+                    state.seat(&name, conn, reply);
+                    return Ok(());
+                }
+                // The journal is only opened once the last run of the channel closed it,
+                // which takes the state it hands us on stopping to be dropped first
+                match state.opening.entry(name.clone()) {
+                    Entry::Occupied(mut waiting) => waiting.get_mut().push((conn, reply)),
+                    Entry::Vacant(waiting) => {
+                        waiting.insert(vec![(conn, reply)]);
+                        let history = state.history.clone();
+                        tokio::spawn(async move {
+                            let opened = History::open(&history, &name).await;
+                            let _ = myself.send_message(Message::Opened(name, opened));
+                        });
+                    }
+                }
+            }
+            Message::Opened(name, opened) => {
+                // Dropping the replies turns the connections away
+                let waiting = state.opening.remove(&name).unwrap_or_default();
+                let spawned = match opened {
+                    Ok(opened) => {
+                        spawn_channel(
+                            &name,
+                            state.topics.clone(),
+                            state.selector,
+                            &state.topology,
+                            opened,
+                            myself.get_cell(),
+                        )
+                        .await
+                    }
+                    Err(err) => Err(err),
+                };
+                match spawned {
+                    Ok(entry) => {
+                        state.channels.insert(name.clone(), entry);
+                        for (conn, reply) in waiting {
+                            state.seat(&name, conn, reply);
+                        }
+                    }
+                    Err(err) => println!("Failed to start channel {name}: {err}"),
+                }
+            }
+            Message::Leave(name, id) => state.leave(&name, id),
+            Message::Publish(name, msg, skip, written) => {
+                if let Some(entry) = state.channels.get(&name) {
+                    // The channel is being restarted, the message is lost like every
+                    // other one sent to it in the meantime
+                    let msg = channel::Message::Message(msg, skip, written);
+                    if let Err(err) = entry.channel.send_message(msg) {
+                        println!("Dropping message for channel {name}: {err}");
+                    }
+                } else {
+                    // Nobody holds a seat, but pattern subscribers can still match it
+                    let matched = state
+                        .topics
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .matches(&name);
+                    let msg = Broadcast::new(&name, None, msg);
+                    for (id, conn) in matched {
+                        if Some(id) == skip {
+                            continue;
+                        }
+                        if conn
+                            .send_message(connection::Message::Match(msg.clone()))
+                            .is_err()
+                        {
+                            println!("Connection Closed");
+                        }
+                    }
+                    if let Some(written) = written {
+                        let _ = written.send(Ok(()));
+                    }
+                }
+            }
+            Message::Presence(name, reply) => {
+                let members = state.channels.get(&name).map(|entry| {
+                    entry
+                        .members
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .list()
+                });
+                let _ = reply.send(members.unwrap_or_default());
+            }
+            Message::SubscribePattern(pattern, conn) => {
+                state
+                    .topics
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(&pattern, conn.get_id(), conn);
+            }
+            Message::UnsubscribePattern(pattern, id) => {
+                state
+                    .topics
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .remove(&pattern, &id);
+            }
+            Message::Rebalance => {
+                if let Some(autoscale) = &state.autoscale {
+                    for (name, entry) in state.channels.iter_mut() {
+                        if let Err(err) = entry.rebalance(autoscale, &myself.get_cell()).await {
+                            println!("Failed to rebalance channel {name}: {err}");
+                        }
+                    }
+                }
+            }
+            Message::Stop(reply) => {
+                // Children still running once the registry stopped get killed
+                for (name, entry) in state.channels.drain() {
+                    entry.shutdown().await;
+                    println!("Channel {name} stopped");
+                }
+                let _ = reply.send(());
+                myself.stop(None);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Channels and balancers also stop when their channel is torn down or after a
+    // merge, only the ones that failed get restarted
+    async fn handle_supervisor_evt(
+        &self,
+        myself: ActorRef<Self::Msg>,
+        message: SupervisionEvent,
+        state: &mut Self::State,
+    ) -> Result<(), ActorProcessingErr> {
+        if let SupervisionEvent::ActorPanicked(actor, err) = message {
+            println!("Actor {} failed: {err}", actor.get_id());
+            state.restart(actor.get_id(), myself.get_cell()).await;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use std::str::FromStr;
+
+use rand::Rng;
+
+/// Picks which of a channel's leaf balancers a new connection attaches to.
+///
+/// `load` returns the number of attendies the balancer at an index last reported.
+pub trait BalancerSelector: Send {
+    fn select(&mut self, count: usize, load: &dyn Fn(usize) -> usize) -> Option<usize>;
+}
+
+/// Uniformly random, ignores load
+pub struct Random;
+
+impl BalancerSelector for Random {
+    fn select(&mut self, count: usize, _load: &dyn Fn(usize) -> usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+
+        Some(rand::thread_rng().gen_range(0..count))
+    }
+}
+
+/// Cycles through the balancers, ignores load
+#[derive(Default)]
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl BalancerSelector for RoundRobin {
+    fn select(&mut self, count: usize, _load: &dyn Fn(usize) -> usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+
+        let index = self.next % count;
+        self.next = index + 1;
+
+        Some(index)
+    }
+}
+
+/// Scans every balancer for the one with the fewest attendies
+pub struct LeastAttendees;
+
+impl BalancerSelector for LeastAttendees {
+    fn select(&mut self, count: usize, load: &dyn Fn(usize) -> usize) -> Option<usize> {
+        (0..count).min_by_key(|&index| load(index))
+    }
+}
+
+/// Samples two random balancers and keeps the one with the fewest attendies.
+/// Close to [LeastAttendees] without reading every balancer's load, and less prone
+/// to piling a burst of joins onto the same balancer while loads are stale.
+pub struct PowerOfTwoChoices;
+
+impl BalancerSelector for PowerOfTwoChoices {
+    fn select(&mut self, count: usize, load: &dyn Fn(usize) -> usize) -> Option<usize> {
+        if count < 2 {
+            return count.checked_sub(1);
+        }
+
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..count);
+        // Pick from the remaining balancers so the two choices differ
+        let second = (first + rng.gen_range(1..count)) % count;
+
+        if load(second) < load(first) {
+            Some(second)
+        } else {
+            Some(first)
+        }
+    }
+}
+
+/// The available [BalancerSelector]s, each channel gets its own instance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    Random,
+    RoundRobin,
+    #[default]
+    LeastAttendees,
+    PowerOfTwoChoices,
+}
+
+impl Strategy {
+    pub fn build(self) -> Box<dyn BalancerSelector> {
+        match self {
+            Strategy::Random => Box::new(Random),
+            Strategy::RoundRobin => Box::new(RoundRobin::default()),
+            Strategy::LeastAttendees => Box::new(LeastAttendees),
+            Strategy::PowerOfTwoChoices => Box::new(PowerOfTwoChoices),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-attendees" => Ok(Strategy::LeastAttendees),
+            "power-of-two-choices" => Ok(Strategy::PowerOfTwoChoices),
+            _ => Err(format!("unknown balancer selector `{s}`")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_robin_cycles() {
+        let mut selector = RoundRobin::default();
+        let picks: Vec<_> = (0..5).map(|_| selector.select(3, &|_| 0).unwrap()).collect();
+
+        assert_eq!(picks, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn least_attendees_picks_the_minimum() {
+        let loads = [4, 2, 7, 1, 3];
+
+        assert_eq!(LeastAttendees.select(loads.len(), &|i| loads[i]), Some(3));
+    }
+
+    #[test]
+    fn power_of_two_choices_never_picks_the_most_loaded() {
+        let loads = [1, 1, 9];
+        for _ in 0..100 {
+            assert_ne!(PowerOfTwoChoices.select(loads.len(), &|i| loads[i]), Some(2));
+        }
+    }
+
+    #[test]
+    fn no_balancers_selects_nothing() {
+        for strategy in [
+            Strategy::Random,
+            Strategy::RoundRobin,
+            Strategy::LeastAttendees,
+            Strategy::PowerOfTwoChoices,
+        ] {
+            assert_eq!(strategy.build().select(0, &|_| 0), None);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::balancer;
+use super::channel;
+use crate::error::Error;
+use ractor::{Actor, ActorCell, ActorRef};
+
+/// The shape of the balancer tree in front of every channel.
+///
+/// The channel gets `layers[0]` balancers, each of those gets `layers[1]` balancers
+/// and so on. Connections attach to the last layer, or straight to the channel when
+/// there are no layers at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    pub layers: Vec<usize>,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            layers: vec![5, 50],
+        }
+    }
+}
+
+/// Parses a comma separated fan-out per layer, e.g. `5,50`, or `none` for no layers
+impl FromStr for Topology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() || s == "none" {
+            return Ok(Self { layers: Vec::new() });
+        }
+
+        let layers = s
+            .split(',')
+            .map(|layer| match layer.trim().parse::<usize>() {
+                Ok(0) | Err(_) => Err(format!("invalid balancer layer `{layer}`")),
+                Ok(count) => Ok(count),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { layers })
+    }
+}
+
+/// Where connections attach to a channel
+pub struct Leaf {
+    pub upstream: balancer::UpstreamActor,
+    pub load: balancer::Load,
+    /// What the leaf balancer itself is attached to, [None] when the leaf is the channel
+    pub parent: Option<balancer::UpstreamActor>,
+}
+
+/// A balancer together with what it is attached to, so it can be restarted in place
+pub struct Node {
+    pub balancer: ActorRef<balancer::Message>,
+    pub parent: balancer::UpstreamActor,
+}
+
+/// The balancers spawned for a channel
+pub struct BalancerTree {
+    /// Every balancer, parents before their children
+    pub balancers: Vec<Node>,
+    pub leaves: Vec<Leaf>,
+}
+
+/// Spawns a balancer attached to `parent`, linked to `supervisor`
+pub async fn spawn_balancer(
+    parent: &balancer::UpstreamActor,
+    supervisor: ActorCell,
+) -> Result<(Node, Leaf), Error> {
+    let load = balancer::Load::default();
+    let (balancer_actor, _handle) = Actor::spawn_linked(
+        None,
+        balancer::Balancer,
+        balancer::BalancerArguments {
+            upstream: parent.clone(),
+            load: load.clone(),
+        },
+        supervisor,
+    )
+    .await?;
+
+    let leaf = Leaf {
+        upstream: balancer::UpstreamActor::Balancer(balancer_actor.clone()),
+        load,
+        parent: Some(parent.clone()),
+    };
+    let node = Node {
+        balancer: balancer_actor,
+        parent: parent.clone(),
+    };
+
+    Ok((node, leaf))
+}
+
+pub async fn spawn_tree(
+    channel_actor: &ActorRef<channel::Message>,
+    topology: &Topology,
+    supervisor: &ActorCell,
+) -> Result<BalancerTree, Error> {
+    let mut balancers = Vec::new();
+    // The channel doesn't report a load, with nothing to choose from it's never read
+    let mut leaves = vec![Leaf {
+        upstream: balancer::UpstreamActor::Channel(channel_actor.clone()),
+        load: balancer::Load::default(),
+        parent: None,
+    }];
+
+    for &fan_out in &topology.layers {
+        let mut layer = Vec::with_capacity(leaves.len() * fan_out);
+        for parent in &leaves {
+            for _ in 0..fan_out {
+                match spawn_balancer(&parent.upstream, supervisor.clone()).await {
+                    Ok((node, leaf)) => {
+                        balancers.push(node);
+                        layer.push(leaf);
+                    }
+                    Err(err) => {
+                        // Don't leave half a tree behind
+                        for node in balancers {
+                            node.balancer.stop(None);
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+        }
+        leaves = layer;
+    }
+
+    Ok(BalancerTree { balancers, leaves })
+}
+
+/// When to grow and shrink the last layer of the balancer tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Autoscale {
+    /// A leaf balancer with more attendies than this is split in two
+    pub split_above: usize,
+    /// A leaf balancer with fewer attendies than this is merged into a sibling
+    pub merge_below: usize,
+    pub interval: Duration,
+}
+
+/// Parses `split_above,merge_below` with an optional third `interval_ms`, e.g. `200,20,1000`
+impl FromStr for Autoscale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid autoscale `{s}`: {err}"))?;
+
+        let (split_above, merge_below, interval_ms) = match values[..] {
+            [split_above, merge_below] => (split_above, merge_below, 1000),
+            [split_above, merge_below, interval_ms] => (split_above, merge_below, interval_ms),
+            _ => return Err(format!("invalid autoscale `{s}`")),
+        };
+        // Every balancer with an attendie would be split, and a timer can't fire every 0ms
+        if split_above == 0 || interval_ms == 0 {
+            return Err(format!("invalid autoscale `{s}`: values must not be 0"));
+        }
+        // Two merged balancers must not be over the split threshold, or they'd flap
+        if merge_below * 2 > split_above {
+            return Err(format!(
+                "autoscale merge threshold {merge_below} must be at most half of {split_above}"
+            ));
+        }
+
+        Ok(Self {
+            split_above,
+            merge_below,
+            interval: Duration::from_millis(interval_ms as u64),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_layers() {
+        assert_eq!("5,50".parse(), Ok(Topology { layers: vec![5, 50] }));
+        assert_eq!(" 3, 4 ,5".parse(), Ok(Topology { layers: vec![3, 4, 5] }));
+    }
+
+    #[test]
+    fn parses_no_layers() {
+        assert_eq!("none".parse(), Ok(Topology { layers: vec![] }));
+        assert_eq!("".parse(), Ok(Topology { layers: vec![] }));
+    }
+
+    #[test]
+    fn parses_autoscale() {
+        assert_eq!(
+            "200,20".parse(),
+            Ok(Autoscale {
+                split_above: 200,
+                merge_below: 20,
+                interval: Duration::from_secs(1),
+            })
+        );
+        assert_eq!(
+            "200,20,250".parse::<Autoscale>().map(|autoscale| autoscale.interval),
+            Ok(Duration::from_millis(250))
+        );
+        assert!("200,150".parse::<Autoscale>().is_err());
+        assert!("200".parse::<Autoscale>().is_err());
+        assert!("0,0".parse::<Autoscale>().is_err());
+        assert!("200,20,0".parse::<Autoscale>().is_err());
+    }
+
+    #[test]
+    fn rejects_empty_layers() {
+        assert!("5,0".parse::<Topology>().is_err());
+        assert!("5,,50".parse::<Topology>().is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! At-least-once delivery for clients that ask for it with `?ack=true`.
+//!
+//! Such a client acknowledges the messages it got with
+//! `{"op":"ack","channel":"x","seq":42}`, which covers every message of the channel up to
+//! sequence number 42. Its session keeps whatever it sent but didn't get an ack for yet
+//! in a [Window], and sends it again when no ack came in [Config::timeout_ms], so the
+//! client may get a message more than once and tells by its `seq`. A client that lets
+//! the window fill up is disconnected, and gets everything in it again when it resumes
+//! its session, see [super::resume]. Binary messages go out as envelopes as well, so
+//! they can be acknowledged. Messages from channels only matched by a pattern have no
+//! sequence number and go out once, as do direct messages. How long the acks took
+//! shows up in `/connections`, see [crate::outbox::Counters].
+
+use std::collections::VecDeque;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::config;
+use crate::error::Error;
+use crate::protocol::Broadcast;
+
+/// The `[ack]` section of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How many messages a client may leave unacknowledged
+    pub window: NonZeroUsize,
+    /// How long to wait for an ack before sending a message again
+    pub timeout_ms: u64,
+}
+
+impl Config {
+    /// Overrides the file with `ACK_WINDOW` and `ACK_TIMEOUT_MS`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(window) = config::env("ACK_WINDOW")? {
+            self.window = window;
+        }
+        if let Some(ms) = config::env("ACK_TIMEOUT_MS")? {
+            self.timeout_ms = ms;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            window: NonZeroUsize::new(100).expect("not zero"),
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// A broadcast waiting for its ack
+#[derive(Debug)]
+struct Pending {
+    msg: Arc<Broadcast>,
+    first_sent: Instant,
+    /// When it went out last, it is due again [Config::timeout_ms] after
+    sent: Instant,
+}
+
+/// What a session sent but didn't get an ack for yet, oldest first
+#[derive(Debug)]
+pub struct Window {
+    capacity: usize,
+    timeout: Duration,
+    pending: VecDeque<Pending>,
+}
+
+impl Window {
+    pub fn new(config: Config) -> Self {
+        Self {
+            capacity: config.window.get(),
+            timeout: Duration::from_millis(config.timeout_ms),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Keep a broadcast that is about to go out until it is acknowledged, fails when the
+    /// window was full already. It is kept all the same, so the client gets it when it
+    /// resumes.
+    pub fn sent(&mut self, msg: &Arc<Broadcast>) -> Result<(), Error> {
+        let full = self.pending.len() >= self.capacity;
+        let now = Instant::now();
+        self.pending.push_back(Pending {
+            msg: msg.clone(),
+            first_sent: now,
+            sent: now,
+        });
+
+        match full {
+            true => Err(Error::Unacked(self.capacity)),
+            false => Ok(()),
+        }
+    }
+
+    /// Forget every broadcast of the channel up to `seq`, returns how long after it was
+    /// first sent each one was acknowledged
+    pub fn ack(&mut self, channel: &str, seq: u64) -> Vec<Duration> {
+        let now = Instant::now();
+        let mut latencies = Vec::new();
+        self.pending.retain(|pending| {
+            let acked = pending.msg.channel() == channel
+                && pending.msg.seq().is_some_and(|msg_seq| msg_seq <= seq);
+            if acked {
+                latencies.push(now - pending.first_sent);
+            }
+            !acked
+        });
+
+        latencies
+    }
+
+    /// Forget every broadcast of a channel we left
+    pub fn forget(&mut self, channel: &str) {
+        self.pending
+            .retain(|pending| pending.msg.channel() != channel);
+    }
+
+    /// Waits until a broadcast is due to be sent again, never when there is none
+    pub async fn due(window: Option<&Self>) {
+        let next = window.and_then(|window| {
+            let sent = window.pending.iter().map(|pending| pending.sent).min()?;
+            Some(sent + window.timeout)
+        });
+        match next {
+            Some(due) => tokio::time::sleep_until(due).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Every broadcast whose ack is overdue, counted as sent again now
+    pub fn overdue(&mut self) -> Vec<Arc<Broadcast>> {
+        let now = Instant::now();
+        self.pending
+            .iter_mut()
+            .filter(|pending| pending.sent + self.timeout <= now)
+            .map(|pending| {
+                pending.sent = now;
+                pending.msg.clone()
+            })
+            .collect()
+    }
+
+    /// Every broadcast still waiting for its ack
+    pub fn unacked(&self) -> Vec<Arc<Broadcast>> {
+        self.pending
+            .iter()
+            .map(|pending| pending.msg.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Publication;
+
+    fn window(window: usize, timeout_ms: u64) -> Window {
+        Window::new(Config {
+            window: window.try_into().unwrap(),
+            timeout_ms,
+        })
+    }
+
+    fn sent(window: &mut Window, channel: &str, seq: u64) -> Result<(), Error> {
+        let msg = Publication::new(1, seq.to_string().into());
+        window.sent(&Broadcast::new(channel, Some(seq), msg))
+    }
+
+    fn seqs(window: &Window) -> Vec<(String, u64)> {
+        window
+            .unacked()
+            .iter()
+            .map(|msg| (msg.channel().to_string(), msg.seq().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn acks_cover_everything_of_the_channel_up_to_them() {
+        let mut window = window(10, 5000);
+        for seq in 1..=3 {
+            sent(&mut window, "room", seq).unwrap();
+        }
+        sent(&mut window, "lobby", 1).unwrap();
+
+        assert_eq!(window.ack("room", 2).len(), 2);
+        assert_eq!(
+            seqs(&window),
+            vec![("room".to_string(), 3), ("lobby".to_string(), 1)]
+        );
+        window.forget("lobby");
+        assert_eq!(seqs(&window), vec![("room".to_string(), 3)]);
+    }
+
+    #[test]
+    fn a_full_window_fails() {
+        let mut window = window(2, 5000);
+        sent(&mut window, "room", 1).unwrap();
+        sent(&mut window, "room", 2).unwrap();
+
+        assert!(matches!(
+            sent(&mut window, "room", 3),
+            Err(Error::Unacked(2))
+        ));
+        assert_eq!(seqs(&window).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn unacked_broadcasts_fall_due() {
+        let mut window = window(10, 0);
+        assert!(window.overdue().is_empty());
+        sent(&mut window, "room", 1).unwrap();
+
+        Window::due(Some(&window)).await;
+        assert_eq!(window.overdue().len(), 1);
+        window.ack("room", 1);
+        assert!(window.overdue().is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+//! Every connected session by its id, so one connection can message another directly.
+//!
+//! Direct messages skip the [super::Backend] altogether, they go straight to the
+//! [Subscriber] of the connection they are for, whichever backend it runs on.
+
+use std::collections::HashMap;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use super::session::{ConnectionId, Subscriber};
+
+#[derive(Debug, Clone, Default)]
+pub struct Directory {
+    sessions: Arc<RwLock<HashMap<ConnectionId, Subscriber>>>,
+}
+
+impl Directory {
+    pub fn register(&self, subscriber: Subscriber) {
+        self.sessions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(subscriber.get_id(), subscriber);
+    }
+
+    pub fn unregister(&self, id: ConnectionId) {
+        self.sessions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id);
+    }
+
+    /// Returns [None] once the connection is gone
+    pub fn get(&self, id: ConnectionId) -> Option<Subscriber> {
+        self.sessions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+            .cloned()
+    }
+}
@@ -0,0 +1,124 @@
+//! Server initiated pings, a client that stops answering them gets disconnected.
+//!
+//! A half-open TCP connection never fails a write nor sends a close frame, without
+//! pings its session would hold on to its channels forever.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+use crate::config;
+use crate::error::Error;
+
+/// The `[heartbeat]` section of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Seconds between two pings, 0 turns them off
+    pub interval_secs: u64,
+    /// How many pings in a row may go unanswered before the client is disconnected
+    pub max_missed: NonZeroU32,
+}
+
+impl Config {
+    /// Overrides the file with `HEARTBEAT_INTERVAL_SECS` and `HEARTBEAT_MAX_MISSED`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(secs) = config::env("HEARTBEAT_INTERVAL_SECS")? {
+            self.interval_secs = secs;
+        }
+        if let Some(max_missed) = config::env("HEARTBEAT_MAX_MISSED")? {
+            self.max_missed = max_missed;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30,
+            max_missed: NonZeroU32::new(2).expect("not zero"),
+        }
+    }
+}
+
+/// Keeps track of the pings of one connection
+pub struct Heartbeat {
+    interval: Option<Interval>,
+    max_missed: u32,
+    /// Pings sent since the last pong
+    missed: u32,
+}
+
+impl Heartbeat {
+    pub fn new(config: Config) -> Self {
+        Self::every(
+            Duration::from_secs(config.interval_secs),
+            config.max_missed.get(),
+        )
+    }
+
+    /// Ping every `period`, or never when it is zero
+    pub fn every(period: Duration, max_missed: u32) -> Self {
+        let interval = (!period.is_zero()).then(|| {
+            let mut interval = time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+
+        Self {
+            interval,
+            max_missed,
+            missed: 0,
+        }
+    }
+
+    /// Resolves when the next ping is due, or fails when too many went unanswered.
+    /// Never resolves with pings turned off.
+    pub async fn tick(&mut self) -> Result<(), Error> {
+        let Some(interval) = &mut self.interval else {
+            return std::future::pending().await;
+        };
+        interval.tick().await;
+        if self.missed >= self.max_missed {
+            return Err(Error::MissedHeartbeats(self.missed));
+        }
+        self.missed += 1;
+
+        Ok(())
+    }
+
+    pub fn pong(&mut self) {
+        self.missed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unanswered_pings_fail_the_heartbeat() {
+        let mut heartbeat = Heartbeat::every(Duration::from_millis(10), 2);
+
+        assert!(heartbeat.tick().await.is_ok());
+        assert!(heartbeat.tick().await.is_ok());
+        assert!(matches!(
+            heartbeat.tick().await,
+            Err(Error::MissedHeartbeats(2))
+        ));
+    }
+
+    #[tokio::test]
+    async fn pong_resets_the_count() {
+        let mut heartbeat = Heartbeat::every(Duration::from_millis(10), 1);
+
+        for _ in 0..3 {
+            assert!(heartbeat.tick().await.is_ok());
+            heartbeat.pong();
+        }
+    }
+}
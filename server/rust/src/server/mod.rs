@@ -0,0 +1,1353 @@
+//! The HTTP and websocket side of the server, shared by every backend.
+//!
+//! Every socket gets a [session] that speaks the [crate::protocol] with the client and
+//! asks the [Backend] to join, leave and publish to channels. The backend only routes
+//! broadcasts and fans them out, delivering them to each session's [Subscriber].
+
+pub mod ack;
+pub mod directory;
+pub mod heartbeat;
+pub mod resume;
+pub mod session;
+pub mod shutdown;
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::{
+    extract::ws::{WebSocket, WebSocketUpgrade},
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    routing::get,
+    Extension, Router,
+};
+use axum_extra::TypedHeader;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use tower_http::trace::{DefaultMakeSpan, TraceLayer};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::history::{Replay, Replayed};
+use crate::journal;
+use crate::outbox;
+use crate::presence;
+use crate::protocol::Payload;
+pub use session::Subscriber;
+
+//allows to extract the IP of connecting user
+use axum::extract::connect_info::ConnectInfo;
+
+//allows to split the websocket stream into separate TX and RX branches
+use futures::stream::StreamExt;
+
+/// A broker implementation the server can run on, picked with `--backend`.
+///
+/// A session only calls [Backend::join] for channels it isn't in yet and
+/// [Backend::leave] for channels it is in. The channel may be a pattern, see
+/// [crate::topic]. Broadcasts are delivered as [session::Message::Out], or as
+/// [session::Message::Match] when they matched a pattern. Joining and leaving a
+/// channel, not a pattern, updates its [presence::Members] when the [Subscriber] has
+/// an identity.
+pub trait Backend: Clone + Send + Sync + 'static {
+    /// What the backend keeps for every connection, e.g. the channels it holds a seat in
+    type Attendee: Send + 'static;
+
+    fn start(config: &Config) -> impl Future<Output = Self> + Send;
+
+    /// Called once per socket, broadcasts for it go to `subscriber`
+    fn connect(
+        &self,
+        subscriber: Subscriber,
+    ) -> impl Future<Output = Result<Self::Attendee, Error>> + Send;
+
+    /// Returns the broadcasts `replay` asks for, the session sends them ahead of whatever
+    /// is delivered for the channel afterwards, along with the sequence number the
+    /// channel is at. Patterns have no history to replay.
+    fn join(
+        &self,
+        attendee: &mut Self::Attendee,
+        channel: &str,
+        replay: Option<Replay>,
+    ) -> impl Future<Output = Result<Replayed, Error>> + Send;
+
+    fn leave(&self, attendee: &mut Self::Attendee, channel: &str) -> Result<(), Error>;
+
+    /// Who is in the channel, whether or not the connection is in it, see [crate::presence]
+    fn presence(
+        &self,
+        attendee: &mut Self::Attendee,
+        channel: &str,
+    ) -> impl Future<Output = Result<Vec<presence::Member>, Error>> + Send;
+
+    /// Publish to a channel, whether or not the connection is in it. Resolves once the
+    /// channel's journal wrote the broadcast, see [crate::history::History::append].
+    fn publish(
+        &self,
+        attendee: &mut Self::Attendee,
+        channel: &str,
+        msg: Payload,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Wait for a broadcast the backend hands to the session itself rather than to its
+    /// [Subscriber]. The future is dropped whenever the session has something else to
+    /// do first, so it has to be cancel safe. Never resolves by default.
+    fn deliver(
+        &self,
+        _attendee: &mut Self::Attendee,
+    ) -> impl Future<Output = session::Message> + Send {
+        std::future::pending()
+    }
+
+    /// Called once the socket is gone, after leaving every channel
+    fn disconnect(&self, attendee: Self::Attendee);
+
+    /// Stop every channel, called on shutdown once the sessions are gone
+    fn stop(&self) -> impl Future<Output = ()> + Send;
+}
+
+/// The websocket routes, the same for every backend
+fn router<B: Backend>(backend: B) -> Router {
+    Router::new()
+        .route("/global", get(global_ws_handler::<B>))
+        .route("/channel/:name", get(channel_ws_handler::<B>))
+        .route("/ws", get(ws_handler::<B>))
+        .with_state(backend)
+}
+
+/// Serve the backend on `bind` until SIGINT or SIGTERM, see [shutdown]
+pub async fn serve<B: Backend>(bind: SocketAddr, config: Config) {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "example_websockets=debug,tower_http=debug".into()),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let backend = B::start(&config).await;
+    let shutdown = shutdown::Shutdown::new();
+
+    let sockets = Sockets {
+        outbox: outbox::Settings::new(config.outbox),
+        heartbeat: config.heartbeat,
+        shutdown: shutdown.guard(),
+        directory: directory::Directory::default(),
+        resumptions: resume::Resumptions::new(config.resume),
+        ack: config.ack,
+    };
+
+    // build our application with some routes
+    let app = router(backend.clone())
+        .route("/connections", get(outbox::stats))
+        .layer(Extension(sockets.outbox.clone()))
+        .layer(Extension(sockets))
+        // logging so we can see whats going on
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::default().include_headers(true)),
+        );
+
+    // run it with hyper
+    let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
+    tracing::debug!("listening on {}", listener.local_addr().unwrap());
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown::signal())
+    .await
+    .unwrap();
+
+    // Upgraded sockets are left alone by axum, they are closed by their sessions
+    println!("Shutting down");
+    shutdown.trigger();
+    if !shutdown.drain(config.shutdown.drain_timeout()).await {
+        println!("Gave up waiting for connections to close");
+    }
+    backend.stop().await;
+    // The channels hand their broadcasts to the journals without waiting for the disk
+    let journals = journal::closed();
+    if tokio::time::timeout(config.shutdown.drain_timeout(), journals)
+        .await
+        .is_err()
+    {
+        println!("Gave up waiting for the journals to be written");
+    }
+}
+
+/// Everything a socket needs besides the backend, handed to the handlers as an [Extension]
+#[derive(Clone)]
+struct Sockets {
+    outbox: outbox::Settings,
+    heartbeat: heartbeat::Config,
+    shutdown: shutdown::Guard,
+    directory: directory::Directory,
+    resumptions: resume::Resumptions,
+    ack: ack::Config,
+}
+
+/// The query string every socket route takes, e.g. `/channel/room?echo=false`
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct Params {
+    /// See [session::Options::echo]
+    echo: bool,
+    /// See [presence::Identity]
+    user: Option<String>,
+    /// A JSON value, e.g. `meta={"name":"Alice"}`
+    #[serde(deserialize_with = "json")]
+    meta: Option<Value>,
+    /// See [Replay::Last]
+    history: Option<usize>,
+    /// See [Replay::Since]
+    since: Option<u64>,
+    /// See [resume]
+    resume: Option<String>,
+    /// See [ack]
+    ack: bool,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            echo: true,
+            user: None,
+            meta: None,
+            history: None,
+            since: None,
+            resume: None,
+            ack: false,
+        }
+    }
+}
+
+/// Query parameters are strings, a value that isn't JSON is rejected with 400
+fn json<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    serde_json::from_str(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl Params {
+    fn options(self, channel: Option<String>) -> session::Options {
+        let identity = self.user.map(|user| {
+            Arc::new(presence::Identity {
+                user,
+                meta: self.meta.unwrap_or(Value::Null),
+            })
+        });
+
+        session::Options {
+            channel,
+            echo: self.echo,
+            identity,
+            replay: Replay::new(self.history, self.since),
+            resume: self.resume,
+            ack: self.ack,
+        }
+    }
+}
+
+/// Keeps the original `/global` endpoint working as the channel named `global`
+async fn global_ws_handler<B: Backend>(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<Params>,
+    State(backend): State<B>,
+    Extension(sockets): Extension<Sockets>,
+) -> impl IntoResponse {
+    let options = params.options(Some(String::from("global")));
+    upgrade(ws, user_agent, addr, options, backend, sockets)
+}
+
+async fn channel_ws_handler<B: Backend>(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(channel): Path<String>,
+    Query(params): Query<Params>,
+    State(backend): State<B>,
+    Extension(sockets): Extension<Sockets>,
+) -> impl IntoResponse {
+    let options = params.options(Some(channel));
+    upgrade(ws, user_agent, addr, options, backend, sockets)
+}
+
+/// A socket without a channel of its own, it only sees the channels it subscribes to
+async fn ws_handler<B: Backend>(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<Params>,
+    State(backend): State<B>,
+    Extension(sockets): Extension<Sockets>,
+) -> impl IntoResponse {
+    upgrade(ws, user_agent, addr, params.options(None), backend, sockets)
+}
+
+/// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
+/// of websocket negotiation). After this completes, the actual switching from HTTP to
+/// websocket protocol will occur.
+/// This is the last point where we can extract TCP/IP metadata such as IP address of the client
+/// as well as things from HTTP headers such as user-agent of the browser etc.
+fn upgrade<B: Backend>(
+    ws: WebSocketUpgrade,
+    user_agent: Option<TypedHeader<headers::UserAgent>>,
+    addr: SocketAddr,
+    options: session::Options,
+    backend: B,
+    sockets: Sockets,
+) -> impl IntoResponse {
+    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
+        user_agent.to_string()
+    } else {
+        String::from("Unknown browser")
+    };
+    println!("`{user_agent}` at {addr} connected.");
+    // finalize the upgrade process by returning upgrade callback.
+    // we can customize the callback by sending additional info such as address.
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, options, backend, sockets))
+}
+
+/// Actual websocket statemachine (one will be spawned per connection)
+async fn handle_socket<B: Backend>(
+    socket: WebSocket,
+    who: SocketAddr,
+    options: session::Options,
+    backend: B,
+    sockets: Sockets,
+) {
+    let Sockets {
+        outbox,
+        heartbeat,
+        shutdown,
+        directory,
+        resumptions,
+        ack,
+    } = sockets;
+    // By splitting socket we can send and receive at the same time
+    let (sender, receiver) = socket.split();
+    // Frames for the client go through its outbox, see [outbox::Policy]
+    let counters = outbox.register(who.to_string());
+    let sender = outbox::Outbox::new(sender, outbox.config, counters);
+
+    let sender = session::run(
+        backend,
+        sender,
+        receiver,
+        options,
+        heartbeat::Heartbeat::new(heartbeat),
+        shutdown.clone(),
+        directory,
+        resumptions,
+        ack,
+    )
+    .await;
+    // Shutdown waits for the rest of the queue and the close frame to go out
+    sender.flush().await;
+
+    // returning from the handler closes the websocket connection
+    println!("Websocket context {who} destroyed");
+    outbox.unregister(&who.to_string());
+}
+
+/// Every backend has to pass these, see `backend_tests!` at the bottom
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, LazyLock};
+    use std::time::Duration;
+
+    use axum::extract::ws::{close_code, Message};
+    use futures::channel::mpsc;
+    use serde_json::{json, Value};
+
+    use super::heartbeat::Heartbeat;
+    use super::*;
+
+    /// A client on the other end of a session, without a real socket in between
+    struct Client {
+        socket: mpsc::UnboundedSender<Result<Message, axum::Error>>,
+        frames: mpsc::Receiver<Message>,
+    }
+
+    /// Connection ids are unique across tests, so they can all share one
+    static DIRECTORY: LazyLock<directory::Directory> = LazyLock::new(Default::default);
+
+    fn options(channel: Option<&str>) -> session::Options {
+        session::Options {
+            channel: channel.map(String::from),
+            echo: true,
+            identity: None,
+            replay: None,
+            resume: None,
+            ack: false,
+        }
+    }
+
+    impl Client {
+        fn connect<B: Backend>(backend: &B, channel: Option<&str>) -> Self {
+            Self::connect_as(backend, options(channel))
+        }
+
+        fn connect_as<B: Backend>(backend: &B, options: session::Options) -> Self {
+            // The guard of a dropped shutdown never fires
+            let shutdown = shutdown::Shutdown::new().guard();
+            Self::connect_with(
+                backend,
+                options,
+                Heartbeat::every(Duration::ZERO, 0),
+                shutdown,
+            )
+        }
+
+        fn connect_with<B: Backend>(
+            backend: &B,
+            options: session::Options,
+            heartbeat: Heartbeat,
+            shutdown: shutdown::Guard,
+        ) -> Self {
+            Self::resumable(
+                backend,
+                options,
+                heartbeat,
+                shutdown,
+                Default::default(),
+                ack::Config::default(),
+            )
+        }
+
+        /// Like [Client::connect_with], told a token to resume with unless resuming is
+        /// off in `resumptions`, and acknowledging with `ack` if [session::Options::ack]
+        fn resumable<B: Backend>(
+            backend: &B,
+            options: session::Options,
+            heartbeat: Heartbeat,
+            shutdown: shutdown::Guard,
+            resumptions: resume::Resumptions,
+            ack: ack::Config,
+        ) -> Self {
+            let (sink, frames) = mpsc::channel(16);
+            let (socket, stream) = mpsc::unbounded();
+            let outbox = outbox::Outbox::new(sink, outbox::Config::default(), Arc::default());
+            tokio::spawn(session::run(
+                backend.clone(),
+                outbox,
+                stream,
+                options,
+                heartbeat,
+                shutdown,
+                DIRECTORY.clone(),
+                resumptions,
+                ack,
+            ));
+
+            Self { socket, frames }
+        }
+
+        /// A client whose outbox holds a single frame and waits for room, its socket
+        /// takes one more
+        fn blocking<B: Backend>(
+            backend: &B,
+            shutdown: shutdown::Guard,
+            counters: Arc<outbox::Counters>,
+        ) -> Self {
+            let (sink, frames) = mpsc::channel(0);
+            let (socket, stream) = mpsc::unbounded();
+            let config = outbox::Config {
+                capacity: 1,
+                policy: outbox::Policy::Block,
+            };
+            let outbox = outbox::Outbox::new(sink, config, counters);
+            tokio::spawn(session::run(
+                backend.clone(),
+                outbox,
+                stream,
+                options(None),
+                Heartbeat::every(Duration::ZERO, 0),
+                shutdown,
+                DIRECTORY.clone(),
+                Default::default(),
+                ack::Config::default(),
+            ));
+
+            Self { socket, frames }
+        }
+
+        fn send(&self, text: &str) {
+            self.socket
+                .unbounded_send(Ok(Message::Text(text.to_string())))
+                .unwrap();
+        }
+
+        fn send_binary(&self, data: &[u8]) {
+            self.socket
+                .unbounded_send(Ok(Message::Binary(data.to_vec())))
+                .unwrap();
+        }
+
+        fn pong(&self) {
+            self.socket
+                .unbounded_send(Ok(Message::Pong(Vec::new())))
+                .unwrap();
+        }
+
+        /// The next text frame, skipping pings
+        async fn receive(&mut self) -> Option<String> {
+            loop {
+                match tokio::time::timeout(Duration::from_millis(500), self.frames.next()).await {
+                    Ok(Some(Message::Text(text))) => return Some(text),
+                    Ok(Some(Message::Ping(_))) => continue,
+                    _ => return None,
+                }
+            }
+        }
+
+        /// The next event, without the parts that differ from run to run: the id,
+        /// the sender and the timestamp
+        async fn receive_event(&mut self) -> Option<Value> {
+            let mut event: Value = serde_json::from_str(&self.receive().await?).unwrap();
+            for key in ["id", "sender", "timestamp"] {
+                let value = event.as_object_mut()?.remove(key);
+                assert!(value.is_some_and(|value| value.is_u64()), "no {key}");
+            }
+            Some(event)
+        }
+
+        /// The data and the sequence number of every event until none come in
+        async fn receive_stamps(&mut self) -> Vec<(Value, Value)> {
+            let mut stamps = Vec::new();
+            while let Some(event) = self.receive_event().await {
+                stamps.push((event["data"].clone(), event["seq"].clone()));
+            }
+            stamps
+        }
+
+        async fn receive_json(&mut self) -> Option<Value> {
+            Some(serde_json::from_str(&self.receive().await?).unwrap())
+        }
+
+        /// The sender of the next event
+        async fn receive_sender(&mut self) -> Option<Value> {
+            let event: Value = serde_json::from_str(&self.receive().await?).unwrap();
+            Some(event["sender"].clone())
+        }
+
+        async fn receive_binary(&mut self) -> Option<Vec<u8>> {
+            match tokio::time::timeout(Duration::from_millis(500), self.frames.next()).await {
+                Ok(Some(Message::Binary(data))) => Some(data),
+                _ => None,
+            }
+        }
+
+        /// The code of the close frame, skipping everything before it
+        async fn closed(&mut self) -> Option<u16> {
+            loop {
+                match tokio::time::timeout(Duration::from_millis(500), self.frames.next()).await {
+                    Ok(Some(Message::Close(frame))) => return frame.map(|frame| frame.code),
+                    Ok(Some(_)) => continue,
+                    _ => return None,
+                }
+            }
+        }
+    }
+
+    /// Joining takes a few messages between actors, let them land before publishing
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    async fn url_channel_gets_envelopes<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, Some("room"));
+        let mut bob = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        alice.send("hello");
+
+        let expected = json!({"op": "message", "channel": "room", "seq": 1, "data": "hello"});
+        assert_eq!(bob.receive_event().await, Some(expected.clone()));
+        assert_eq!(alice.receive_event().await, Some(expected));
+    }
+
+    async fn envelopes_are_stamped_in_order<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, Some("room"));
+        let bob = Client::connect(&backend, Some("room"));
+        let carol = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        bob.send("a");
+        bob.send("b");
+        // Sessions publish independently, only what one of them sends keeps its order
+        settle().await;
+        carol.send("c");
+
+        let mut events = Vec::new();
+        while let Some(text) = alice.receive().await {
+            events.push(serde_json::from_str::<Value>(&text).unwrap());
+        }
+        let stamps: Vec<_> = events
+            .iter()
+            .map(|event| (event["data"].clone(), event["seq"].clone()))
+            .collect();
+        assert_eq!(
+            stamps,
+            vec![
+                (json!("a"), json!(1)),
+                (json!("b"), json!(2)),
+                (json!("c"), json!(3))
+            ]
+        );
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0]["id"].as_u64() < pair[1]["id"].as_u64()));
+        assert_eq!(events[0]["sender"], events[1]["sender"]);
+        assert_ne!(events[1]["sender"], events[2]["sender"]);
+    }
+
+    async fn subscribers_get_events<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, None);
+        let bob = Client::connect(&backend, Some("room"));
+        alice.send(r#"{"op":"subscribe","channel":"room"}"#);
+        settle().await;
+
+        bob.send("hello");
+
+        assert_eq!(
+            alice.receive_event().await,
+            Some(json!({"op": "message", "channel": "room", "seq": 1, "data": "hello"}))
+        );
+    }
+
+    async fn patterns_match_channels<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, None);
+        let bob = Client::connect(&backend, None);
+        alice.send(r#"{"op":"subscribe","channel":"sports.*"}"#);
+        settle().await;
+
+        bob.send(r#"{"op":"publish","channel":"news.world","data":"skipped"}"#);
+        bob.send(r#"{"op":"publish","channel":"sports.nfl","data":"touchdown"}"#);
+
+        // Nobody is in the channel, so there is no sequence to count in
+        assert_eq!(
+            alice.receive_event().await,
+            Some(json!({"op": "message", "channel": "sports.nfl", "data": "touchdown"}))
+        );
+    }
+
+    async fn unsubscribed_clients_get_nothing<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, None);
+        let bob = Client::connect(&backend, Some("room"));
+        alice.send(r#"{"op":"subscribe","channel":"room"}"#);
+        alice.send(r#"{"op":"unsubscribe","channel":"room"}"#);
+        settle().await;
+
+        bob.send("hello");
+
+        assert_eq!(alice.receive().await, None);
+    }
+
+    async fn binary_frames_stay_binary<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, Some("room"));
+        let mut bob = Client::connect(&backend, None);
+        let carol = Client::connect(&backend, Some("room"));
+        bob.send(r#"{"op":"subscribe","channel":"room"}"#);
+        settle().await;
+
+        carol.send_binary(&[0, 1, 255]);
+
+        assert_eq!(alice.receive_binary().await, Some(vec![0, 1, 255]));
+        assert_eq!(
+            bob.receive_event().await,
+            Some(json!({
+                "op": "message",
+                "channel": "room",
+                "seq": 1,
+                "data": "AAH/",
+                "encoding": "base64",
+            }))
+        );
+    }
+
+    async fn echo_can_be_turned_off<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let quiet = session::Options {
+            echo: false,
+            ..options(Some("room"))
+        };
+        let mut alice = Client::connect_as(&backend, quiet);
+        let mut bob = Client::connect(&backend, Some("room"));
+        alice.send(r#"{"op":"subscribe","channel":"sports.*"}"#);
+        bob.send(r#"{"op":"subscribe","channel":"sports.*"}"#);
+        settle().await;
+
+        alice.send("hello");
+        alice.send(r#"{"op":"publish","channel":"sports.nfl","data":"touchdown"}"#);
+
+        // The channel and the pattern are reached on different paths
+        let mut received = Vec::new();
+        while let Some(event) = bob.receive_event().await {
+            received.push(event["data"].clone());
+        }
+        received.sort_by_key(|data| data.to_string());
+        assert_eq!(received, vec![json!("hello"), json!("touchdown")]);
+        assert_eq!(alice.receive().await, None);
+    }
+
+    async fn presence_tracks_users<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let member = |user: &str| session::Options {
+            identity: Some(Arc::new(presence::Identity {
+                user: user.to_string(),
+                meta: json!({ "name": user.to_uppercase() }),
+            })),
+            ..options(Some("room"))
+        };
+        let mut bob = Client::connect_as(&backend, member("bob"));
+        settle().await;
+        assert_eq!(
+            bob.receive().await.as_deref(),
+            Some(r#"{"op":"presence_join","channel":"room","user":"bob","meta":{"name":"BOB"}}"#)
+        );
+
+        // Only the first connection of a user is announced
+        let alice = Client::connect_as(&backend, member("alice"));
+        settle().await;
+        let alice_again = Client::connect_as(&backend, member("alice"));
+        settle().await;
+        assert_eq!(
+            bob.receive().await.as_deref(),
+            Some(
+                r#"{"op":"presence_join","channel":"room","user":"alice","meta":{"name":"ALICE"}}"#
+            )
+        );
+        assert_eq!(bob.receive().await, None);
+
+        bob.send(r#"{"op":"presence","channel":"room"}"#);
+        let event: Value = serde_json::from_str(&bob.receive().await.unwrap()).unwrap();
+        assert_eq!(
+            event,
+            json!({
+                "op": "presence",
+                "channel": "room",
+                "members": [
+                    { "user": "alice", "meta": { "name": "ALICE" }, "connections": 2 },
+                    { "user": "bob", "meta": { "name": "BOB" }, "connections": 1 },
+                ],
+            })
+        );
+
+        // And only the last one leaving
+        drop(alice);
+        settle().await;
+        assert_eq!(bob.receive().await, None);
+        drop(alice_again);
+        settle().await;
+        assert_eq!(
+            bob.receive().await.as_deref(),
+            Some(r#"{"op":"presence_leave","channel":"room","user":"alice"}"#)
+        );
+    }
+
+    async fn joining_replays_history<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let alice = Client::connect(&backend, Some("room"));
+        settle().await;
+        for msg in ["a", "b", "c"] {
+            alice.send(msg);
+        }
+        settle().await;
+
+        let catching_up = session::Options {
+            replay: Some(Replay::Last(2)),
+            ..options(Some("room"))
+        };
+        let mut bob = Client::connect_as(&backend, catching_up);
+        let mut carol = Client::connect(&backend, None);
+        settle().await;
+        alice.send("d");
+        carol.send(r#"{"op":"subscribe","channel":"room","since":1}"#);
+
+        let expected = vec![
+            (json!("b"), json!(2)),
+            (json!("c"), json!(3)),
+            (json!("d"), json!(4)),
+        ];
+        assert_eq!(bob.receive_stamps().await, expected);
+        assert_eq!(carol.receive_stamps().await, expected);
+    }
+
+    async fn replay_hands_over_to_live_delivery<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let alice = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        // Bob joins while alice is publishing, the sessions take turns in between
+        let catching_up = session::Options {
+            replay: Some(Replay::Since(0)),
+            ..options(Some("room"))
+        };
+        let mut bob = None;
+        for msg in 1..=50 {
+            alice.send(&msg.to_string());
+            if msg == 10 {
+                bob = Some(Client::connect_as(&backend, catching_up.clone()));
+            }
+            tokio::task::yield_now().await;
+        }
+        let mut bob = bob.unwrap();
+
+        let seqs: Vec<_> = bob
+            .receive_stamps()
+            .await
+            .into_iter()
+            .map(|(_, seq)| seq)
+            .collect();
+        assert_eq!(seqs, (1..=50).map(|seq| json!(seq)).collect::<Vec<_>>());
+    }
+
+    async fn journal_survives_restarts<B: Backend>() {
+        let dir = std::env::temp_dir().join(format!(
+            "ws-server-restart-{}-{}",
+            std::process::id(),
+            std::any::type_name::<B>().replace("::", "-")
+        ));
+        let mut config = Config::default();
+        config.history.journal.dir = Some(dir.clone());
+
+        let backend = B::start(&config).await;
+        let alice = Client::connect(&backend, Some("room"));
+        settle().await;
+        alice.send("a");
+        alice.send("b");
+        settle().await;
+        drop(alice);
+        settle().await;
+        backend.stop().await;
+
+        // A new server on the same journal carries on where the last one stopped
+        let backend = B::start(&config).await;
+        let resuming = session::Options {
+            replay: Some(Replay::Since(0)),
+            ..options(Some("room"))
+        };
+        let mut bob = Client::connect_as(&backend, resuming);
+        settle().await;
+        bob.send("c");
+
+        let expected = vec![
+            (json!("a"), json!(1)),
+            (json!("b"), json!(2)),
+            (json!("c"), json!(3)),
+        ];
+        assert_eq!(bob.receive_stamps().await, expected);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    async fn rejoined_channels_carry_on_their_journal<B: Backend>() {
+        let dir = std::env::temp_dir().join(format!(
+            "ws-server-rejoin-{}-{}",
+            std::process::id(),
+            std::any::type_name::<B>().replace("::", "-")
+        ));
+        let mut config = Config::default();
+        config.history.journal.dir = Some(dir.clone());
+        let backend = B::start(&config).await;
+
+        // Every run of the channel opens the journal once the last one closed it
+        for msg in ["a", "b", "c"] {
+            let alice = Client::connect(&backend, Some("room"));
+            settle().await;
+            alice.send(msg);
+            settle().await;
+            drop(alice);
+        }
+        let replaying = session::Options {
+            replay: Some(Replay::Since(0)),
+            ..options(Some("room"))
+        };
+        let mut bob = Client::connect_as(&backend, replaying);
+        settle().await;
+        bob.send("d");
+
+        let expected = vec![
+            (json!("a"), json!(1)),
+            (json!("b"), json!(2)),
+            (json!("c"), json!(3)),
+            (json!("d"), json!(4)),
+        ];
+        assert_eq!(bob.receive_stamps().await, expected);
+        drop(bob);
+        settle().await;
+        backend.stop().await;
+        journal::closed().await;
+        let mut lines = 0;
+        for segment in std::fs::read_dir(dir.join("room")).unwrap() {
+            lines += std::fs::read_to_string(segment.unwrap().path())
+                .unwrap()
+                .lines()
+                .count();
+        }
+        assert_eq!(lines, 4);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    async fn dropped_sessions_resume<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let resumptions = resume::Resumptions::new(resume::Config::default());
+        let connect = |options| {
+            let heartbeat = Heartbeat::every(Duration::ZERO, 0);
+            let shutdown = shutdown::Shutdown::new().guard();
+            Client::resumable(
+                &backend,
+                options,
+                heartbeat,
+                shutdown,
+                resumptions.clone(),
+                ack::Config::default(),
+            )
+        };
+        let resuming = |token: &Value| session::Options {
+            resume: token.as_str().map(String::from),
+            ..options(None)
+        };
+
+        let mut alice = connect(options(Some("room")));
+        let session = alice.receive_json().await.unwrap();
+        assert_eq!(session["resumed"], false);
+        alice.send(r#"{"op":"subscribe","channel":"sports.*"}"#);
+        alice.send(r#"{"op":"subscribe","channel":"lobby"}"#);
+        alice.send("a");
+        alice.send(r#"{"op":"publish","channel":"lobby","data":"x"}"#);
+        assert_eq!(alice.receive_stamps().await.len(), 2);
+        let bob = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        // Alice's socket drops, she misses what bob says until she is back. The lobby
+        // stops without her and starts over with bob.
+        drop(alice);
+        settle().await;
+        bob.send("b");
+        bob.send("c");
+        bob.send(r#"{"op":"subscribe","channel":"lobby"}"#);
+        bob.send(r#"{"op":"publish","channel":"lobby","data":"e"}"#);
+        settle().await;
+        let mut alice = connect(resuming(&session["token"]));
+
+        let resumed = alice.receive_json().await.unwrap();
+        assert_eq!(resumed["resumed"], true);
+        assert_eq!(resumed["id"], session["id"]);
+        assert_ne!(resumed["token"], session["token"]);
+        let mut missed = alice.receive_stamps().await;
+        missed.sort_by_key(|(data, _)| data.to_string());
+        let expected = vec![
+            (json!("b"), json!(2)),
+            (json!("c"), json!(3)),
+            (json!("e"), json!(1)),
+        ];
+        assert_eq!(missed, expected);
+        bob.send(r#"{"op":"publish","channel":"sports.nfl","data":"d"}"#);
+        assert_eq!(
+            alice.receive_event().await.unwrap()["channel"],
+            "sports.nfl"
+        );
+
+        // A token is only good once, and not at all after closing the socket
+        let mut again = connect(resuming(&session["token"]));
+        assert_eq!(again.receive_json().await.unwrap()["resumed"], false);
+        alice
+            .socket
+            .unbounded_send(Ok(Message::Close(None)))
+            .unwrap();
+        settle().await;
+        let mut closed = connect(resuming(&resumed["token"]));
+        assert_eq!(closed.receive_json().await.unwrap()["resumed"], false);
+    }
+
+    async fn shutdown_closes_with_going_away<B: Backend>() {
+        let backend = B::start(&Config::default()).await;
+        let shutdown = shutdown::Shutdown::new();
+        let heartbeat = Heartbeat::every(Duration::ZERO, 0);
+        let mut alice =
+            Client::connect_with(&backend, options(Some("room")), heartbeat, shutdown.guard());
+        settle().await;
+
+        shutdown.trigger();
+
+        assert_eq!(alice.closed().await, Some(close_code::AWAY));
+        assert!(shutdown.drain(Duration::from_secs(1)).await);
+        backend.stop().await;
+    }
+
+    /// Leaving the channel is up to the session, so this doesn't depend on the backend
+    #[tokio::test]
+    async fn silent_clients_are_reaped() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let shutdown = shutdown::Shutdown::new();
+        let heartbeat = Heartbeat::every(Duration::from_millis(20), 2);
+        let mut alice =
+            Client::connect_with(&backend, options(Some("room")), heartbeat, shutdown.guard());
+        let heartbeat = Heartbeat::every(Duration::from_millis(20), 2);
+        let mut bob =
+            Client::connect_with(&backend, options(Some("room")), heartbeat, shutdown.guard());
+
+        for _ in 0..5 {
+            match tokio::time::timeout(Duration::from_millis(500), bob.frames.next()).await {
+                Ok(Some(Message::Ping(_))) => bob.pong(),
+                frame => panic!("expected a ping, got {frame:?}"),
+            }
+        }
+
+        assert_eq!(alice.closed().await, Some(close_code::POLICY));
+        bob.send("still here");
+        assert_eq!(
+            bob.receive_event().await.map(|event| event["data"].clone()),
+            Some(json!("still here"))
+        );
+    }
+
+    /// Redelivery is up to the session as well
+    #[tokio::test]
+    async fn unacked_messages_are_redelivered() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let resumptions = resume::Resumptions::new(resume::Config::default());
+        let connect = |resume: Option<String>| {
+            let heartbeat = Heartbeat::every(Duration::ZERO, 0);
+            let shutdown = shutdown::Shutdown::new().guard();
+            let options = session::Options {
+                resume,
+                ack: true,
+                ..options(Some("room"))
+            };
+            let ack = ack::Config {
+                window: 2.try_into().unwrap(),
+                timeout_ms: 100,
+            };
+            Client::resumable(
+                &backend,
+                options,
+                heartbeat,
+                shutdown,
+                resumptions.clone(),
+                ack,
+            )
+        };
+        let mut alice = connect(None);
+        let session = alice.receive_json().await.unwrap();
+        let bob = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        // Sent again until alice acknowledges it
+        bob.send("a");
+        for _ in 0..2 {
+            let event = alice.receive_event().await.unwrap();
+            assert_eq!((&event["data"], &event["seq"]), (&json!("a"), &json!(1)));
+        }
+        alice.send(r#"{"op":"ack","channel":"room","seq":1}"#);
+        assert_eq!(alice.receive().await, None);
+
+        // Binary messages come as envelopes to be acknowledged, and what alice didn't
+        // acknowledge comes again when she resumes, before what she missed
+        bob.send_binary(&[1]);
+        let event = alice.receive_event().await.unwrap();
+        assert_eq!(
+            (&event["encoding"], &event["seq"]),
+            (&json!("base64"), &json!(2))
+        );
+        drop(alice);
+        settle().await;
+        bob.send("c");
+        settle().await;
+        let mut alice = connect(session["token"].as_str().map(String::from));
+        let session = alice.receive_json().await.unwrap();
+        assert_eq!(session["resumed"], true);
+        for (data, seq) in [(json!("AQ=="), json!(2)), (json!("c"), json!(3))] {
+            let event = alice.receive_event().await.unwrap();
+            assert_eq!((&event["data"], &event["seq"]), (&data, &seq));
+        }
+
+        // Her window only takes two, what overflowed it still comes when she resumes
+        bob.send("d");
+        assert_eq!(alice.closed().await, Some(close_code::POLICY));
+        settle().await;
+        let mut alice = connect(session["token"].as_str().map(String::from));
+        assert_eq!(alice.receive_json().await.unwrap()["resumed"], true);
+        let expected = [(json!("AQ=="), 2), (json!("c"), 3), (json!("d"), 4)];
+        for (data, seq) in expected {
+            let event = alice.receive_event().await.unwrap();
+            assert_eq!((&event["data"], &event["seq"]), (&data, &json!(seq)));
+        }
+        alice.send(r#"{"op":"ack","channel":"room","seq":4}"#);
+        assert_eq!(alice.receive().await, None);
+    }
+
+    /// Only what made it to the socket counts as delivered when the session resumes
+    #[tokio::test]
+    async fn queued_broadcasts_are_resumed() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let resumptions = resume::Resumptions::new(resume::Config::default());
+        let connect = |options| {
+            let heartbeat = Heartbeat::every(Duration::ZERO, 0);
+            let shutdown = shutdown::Shutdown::new().guard();
+            let ack = ack::Config::default();
+            Client::resumable(
+                &backend,
+                options,
+                heartbeat,
+                shutdown,
+                resumptions.clone(),
+                ack,
+            )
+        };
+        let alice = connect(options(Some("room")));
+        let bob = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        // Alice reads nothing, her socket takes 17 frames and the rest stays queued
+        for n in 1..=30 {
+            bob.send(&n.to_string());
+        }
+        settle().await;
+        let Client { socket, mut frames } = alice;
+        let session: Value = match frames.next().await {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            frame => panic!("expected the session event, got {frame:?}"),
+        };
+        drop(socket);
+        settle().await;
+
+        let mut alice = connect(session::Options {
+            resume: session["token"].as_str().map(String::from),
+            ..options(None)
+        });
+        assert_eq!(alice.receive_json().await.unwrap()["resumed"], true);
+        let seqs: Vec<Value> = (17..=30).map(|seq| json!(seq)).collect();
+        let resumed = alice.receive_stamps().await;
+        assert_eq!(
+            resumed.into_iter().map(|(_, seq)| seq).collect::<Vec<_>>(),
+            seqs
+        );
+    }
+
+    /// Waiting for room in the outbox is up to the session too
+    #[tokio::test]
+    async fn blocked_sessions_still_shut_down() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let shutdown = shutdown::Shutdown::new();
+        let alice = Client::blocking(&backend, shutdown.guard(), Arc::default());
+        let bob = Client::connect(&backend, None);
+        alice.send(r#"{"op":"subscribe","channel":"sports.*"}"#);
+        settle().await;
+
+        // Alice reads nothing, her session waits for room after the first few
+        for n in 1..=10 {
+            bob.send(&format!(
+                r#"{{"op":"publish","channel":"sports.nfl","data":"{n}"}}"#
+            ));
+        }
+        settle().await;
+        shutdown.trigger();
+
+        assert!(shutdown.drain(Duration::from_secs(1)).await);
+        // Her socket stayed open all along
+        drop(alice);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_dropped_at_the_mailbox_count_as_dropped() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let counters = Arc::<outbox::Counters>::default();
+        let shutdown = shutdown::Shutdown::new().guard();
+        let mut alice = Client::blocking(&backend, shutdown, counters.clone());
+        let bob = Client::connect(&backend, None);
+        alice.send(r#"{"op":"subscribe","channel":"sports.*"}"#);
+        settle().await;
+
+        // More than her mailbox holds while her session waits for room
+        for n in 1..=600 {
+            bob.send(&format!(
+                r#"{{"op":"publish","channel":"sports.nfl","data":"{n}"}}"#
+            ));
+        }
+        settle().await;
+
+        let mut received = 0;
+        while alice.receive().await.is_some() {
+            received += 1;
+        }
+        let dropped = counters.snapshot().dropped;
+        assert!(dropped > 0);
+        assert_eq!(received + dropped, 600);
+    }
+
+    /// Direct messages don't go through the backend, so any will do
+    #[tokio::test]
+    async fn direct_messages_reach_only_their_target() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, Some("room"));
+        let mut bob = Client::connect(&backend, Some("room"));
+        let mut carol = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        // Connections learn who is who from the sender of their messages
+        alice.send("hi");
+        let alice_id = carol.receive_sender().await.unwrap();
+        bob.send("hi");
+        let bob_id = carol.receive_sender().await.unwrap();
+        for client in [&mut alice, &mut bob] {
+            client.receive().await;
+            client.receive().await;
+        }
+        bob.send(&format!(
+            r#"{{"op":"direct","to":{alice_id},"data":"psst"}}"#
+        ));
+
+        let event: Value = serde_json::from_str(&alice.receive().await.unwrap()).unwrap();
+        assert_eq!(event["op"], "direct");
+        assert_eq!(event["from"], bob_id);
+        assert_eq!(event["data"], "psst");
+        assert!(event["id"].is_u64() && event["timestamp"].is_u64());
+        assert_eq!(carol.receive().await, None);
+
+        drop(alice);
+        settle().await;
+        bob.send(&format!(
+            r#"{{"op":"direct","to":{alice_id},"data":"still there?"}}"#
+        ));
+        assert_eq!(
+            bob.receive().await,
+            Some(format!(
+                r#"{{"op":"error","message":"connection {alice_id} is not connected"}}"#
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn direct_messages_to_unknown_connections_are_errors() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, None);
+
+        alice.send(r#"{"op":"direct","to":"0","data":"anyone?"}"#);
+
+        assert_eq!(
+            alice.receive().await.as_deref(),
+            Some(r#"{"op":"error","message":"connection 0 is not connected"}"#)
+        );
+        // The connection stays open
+        alice.send(r#"{"op":"direct","to":0,"data":"anyone?"}"#);
+        assert!(alice.receive().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn malformed_commands_are_errors() {
+        let backend = crate::tokio_broadcast::TokioBroadcast::start(&Config::default()).await;
+        let mut alice = Client::connect(&backend, Some("room"));
+        let mut bob = Client::connect(&backend, Some("room"));
+        settle().await;
+
+        alice.send(r#"{"op":"subscribe","chanel":"lobby"}"#);
+
+        let event = alice.receive_json().await.unwrap();
+        assert_eq!(event["op"], "error");
+        assert!(event["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid command: missing field `channel`"));
+        // Nothing was published, and the connection stays open
+        assert_eq!(bob.receive().await, None);
+        alice.send(r#"{"data":"hi"}"#);
+        assert_eq!(
+            bob.receive_event().await.unwrap()["data"],
+            r#"{"data":"hi"}"#
+        );
+    }
+
+    macro_rules! backend_tests {
+        ($name:ident, $backend:ty) => {
+            mod $name {
+                #[tokio::test]
+                async fn url_channel_gets_envelopes() {
+                    super::url_channel_gets_envelopes::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn envelopes_are_stamped_in_order() {
+                    super::envelopes_are_stamped_in_order::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn subscribers_get_events() {
+                    super::subscribers_get_events::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn patterns_match_channels() {
+                    super::patterns_match_channels::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn unsubscribed_clients_get_nothing() {
+                    super::unsubscribed_clients_get_nothing::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn binary_frames_stay_binary() {
+                    super::binary_frames_stay_binary::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn echo_can_be_turned_off() {
+                    super::echo_can_be_turned_off::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn presence_tracks_users() {
+                    super::presence_tracks_users::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn joining_replays_history() {
+                    super::joining_replays_history::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn replay_hands_over_to_live_delivery() {
+                    super::replay_hands_over_to_live_delivery::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn journal_survives_restarts() {
+                    super::journal_survives_restarts::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn rejoined_channels_carry_on_their_journal() {
+                    super::rejoined_channels_carry_on_their_journal::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn dropped_sessions_resume() {
+                    super::dropped_sessions_resume::<$backend>().await;
+                }
+
+                #[tokio::test]
+                async fn shutdown_closes_with_going_away() {
+                    super::shutdown_closes_with_going_away::<$backend>().await;
+                }
+            }
+        };
+    }
+
+    backend_tests!(ractor, crate::ractor::Ractor);
+    backend_tests!(tokio_actors, crate::tokio_actors::TokioActors);
+    backend_tests!(tokio_broadcast, crate::tokio_broadcast::TokioBroadcast);
+}
@@ -0,0 +1,138 @@
+//! Resuming a session after its socket dropped, e.g. a phone switching networks.
+//!
+//! Every session is told its connection id and a token when it connects, see
+//! [crate::protocol::Event::Session]. When its socket drops, it stops answering pings or
+//! it leaves too much unacknowledged, without the client closing it, the session leaves
+//! its channels as usual but parks its subscriptions under the token for
+//! [Config::grace_secs], along with the sequence number of the last broadcast written
+//! to its socket from every channel, so whatever was still queued in its
+//! [crate::outbox::Outbox] isn't lost. A client reconnecting with `?resume=<token>` in
+//! that window gets its connection id and subscriptions back, whatever it didn't
+//! acknowledge, see [super::ack], and every broadcast it missed meanwhile that its
+//! channels still keep, see [crate::history]. Every token is good for one resumption,
+//! the resumed session is told a new one.
+//!
+//! A frame written to the socket may still be lost with the connection on its way to
+//! the client, only clients that acknowledge what they get are sure not to miss it.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use super::session::ConnectionId;
+use crate::config;
+use crate::history::Cursor;
+use crate::protocol::Broadcast;
+
+/// The `[resume]` section of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How long the subscriptions of a dropped session are kept, 0 turns resuming off
+    pub grace_secs: u64,
+}
+
+impl Config {
+    /// Overrides the file with `RESUME_GRACE_SECS`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(secs) = config::env("RESUME_GRACE_SECS")? {
+            self.grace_secs = secs;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { grace_secs: 30 }
+    }
+}
+
+/// What a dropped session leaves behind for the client to pick up
+#[derive(Debug, Clone, Default)]
+pub struct Parked {
+    pub id: ConnectionId,
+    /// Every channel and pattern it was subscribed to
+    pub subscriptions: HashSet<String>,
+    /// How far it got in each channel
+    pub cursors: HashMap<String, Cursor>,
+    /// What it was sent but didn't acknowledge, oldest first
+    pub unacked: Vec<Arc<Broadcast>>,
+}
+
+/// The parked sessions of every socket, handed to each session like the
+/// [super::directory::Directory]
+#[derive(Debug, Clone, Default)]
+pub struct Resumptions {
+    grace: Duration,
+    parked: Arc<Mutex<HashMap<String, (Instant, Parked)>>>,
+}
+
+impl Resumptions {
+    pub fn new(config: Config) -> Self {
+        Self {
+            grace: Duration::from_secs(config.grace_secs),
+            parked: Arc::default(),
+        }
+    }
+
+    fn parked(&self) -> MutexGuard<'_, HashMap<String, (Instant, Parked)>> {
+        self.parked.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// A new token for a session, [None] when resuming is off
+    pub fn issue(&self) -> Option<String> {
+        (!self.grace.is_zero()).then(|| format!("{:032x}", rand::random::<u128>()))
+    }
+
+    /// Keep the session for the grace period, dropping every one whose period is over
+    pub fn park(&self, token: String, parked: Parked) {
+        let now = Instant::now();
+        let mut sessions = self.parked();
+        sessions.retain(|_, (expires, _)| *expires > now);
+        sessions.insert(token, (now + self.grace, parked));
+    }
+
+    /// Hand the session back, [None] when the token is unknown or its period is over
+    pub fn take(&self, token: &str) -> Option<Parked> {
+        let (expires, parked) = self.parked().remove(token)?;
+        (expires > Instant::now()).then_some(parked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resumptions(grace_secs: u64) -> Resumptions {
+        Resumptions::new(Config { grace_secs })
+    }
+
+    #[test]
+    fn tokens_are_only_issued_when_resuming_is_on() {
+        assert!(resumptions(0).issue().is_none());
+        let resumptions = resumptions(30);
+        assert_ne!(resumptions.issue(), resumptions.issue());
+    }
+
+    #[test]
+    fn parked_sessions_are_taken_once_within_the_grace_period() {
+        let parked = Parked {
+            id: 7,
+            ..Parked::default()
+        };
+        let lasting = resumptions(30);
+        lasting.park("token".to_string(), parked.clone());
+
+        assert_eq!(lasting.take("token").map(|parked| parked.id), Some(7));
+        assert!(lasting.take("token").is_none());
+
+        // Without a grace period they are over right away
+        let fleeting = resumptions(0);
+        fleeting.park("token".to_string(), parked);
+        assert!(fleeting.take("token").is_none());
+    }
+}
@@ -0,0 +1,637 @@
+//! One session per websocket, speaking the [crate::protocol] with the client.
+//!
+//! The session reads commands off the socket and turns them into calls on the
+//! [Backend], and writes whatever the backend delivers to its [Subscriber] to the
+//! client's [Outbox]. Direct messages go from session to session through the
+//! [Directory] instead. A session whose socket dropped is parked in the [Resumptions]
+//! for the client to pick up again. A client that acknowledges what it gets has it
+//! sent again until it does, see [super::ack].
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::extract::ws;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use super::ack::{self, Window};
+use super::directory::Directory;
+use super::heartbeat::Heartbeat;
+use super::resume::{Parked, Resumptions};
+use super::shutdown;
+use super::Backend;
+use crate::error::{Error, Recovery};
+use crate::history::{Cursor, Replay};
+use crate::outbox::{Outbox, Pinger};
+use crate::presence::Identity;
+use crate::protocol::{self, Payload};
+use crate::topic;
+
+/// How many deliveries wait for a session before the backend has to drop them
+const MAILBOX_CAPACITY: usize = 500;
+
+pub type ConnectionId = u64;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What a backend delivers to a session
+#[derive(Debug)]
+pub enum Message {
+    /// A broadcast from a channel the connection joined
+    Out(Arc<protocol::Broadcast>),
+    /// A broadcast from a channel matching one of the connection's patterns
+    Match(Arc<protocol::Broadcast>),
+    /// The client read a channel too slowly and `missed` broadcasts from it were skipped
+    Lagged { channel: String, missed: u64 },
+    /// The backend gave up on the connection, e.g. a channel it was in kept failing
+    Close(Error),
+    /// An [protocol::Event::Direct] from another connection
+    Direct(Arc<str>),
+}
+
+/// What the client asked for when it connected
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The channel from the url the socket connected to, if any
+    pub channel: Option<String>,
+    /// Whether the client gets back what it publishes
+    pub echo: bool,
+    /// Who the connection belongs to, anonymous connections are not in any presence list
+    pub identity: Option<Arc<Identity>>,
+    /// What to replay when joining the channel from the url
+    pub replay: Option<Replay>,
+    /// The token of a dropped session to pick up, see [super::resume]
+    pub resume: Option<String>,
+    /// Whether the client acknowledges the broadcasts it gets, see [super::ack]
+    pub ack: bool,
+}
+
+/// Where a backend delivers broadcasts for a connection
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    id: ConnectionId,
+    sender: mpsc::Sender<Message>,
+    echo: bool,
+    identity: Option<Arc<Identity>>,
+    /// Broadcasts the mailbox had no room for, the session counts them as dropped
+    dropped: Arc<AtomicU64>,
+}
+
+impl Subscriber {
+    pub fn get_id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Whether the connection gets back what it publishes, see [Options::echo]
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    /// See [Options::identity]
+    pub fn identity(&self) -> Option<&Arc<Identity>> {
+        self.identity.as_ref()
+    }
+
+    /// Backends leave the broadcast out for this connection when it returns false
+    pub fn wants(&self, msg: &protocol::Broadcast) -> bool {
+        self.echo || msg.sender() != self.id
+    }
+
+    pub fn send_message(&self, msg: Message) -> Result<(), Error> {
+        let broadcast = matches!(msg, Message::Out(_) | Message::Match(_));
+        match self.sender.try_send(msg) {
+            Err(mpsc::error::TrySendError::Full(_)) if broadcast => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                Err(Error::MailboxFull)
+            }
+            sent => Ok(sent?),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Subscriber {
+    /// A subscriber without a session behind it, the test reads its mailbox instead
+    pub fn probe(id: ConnectionId, capacity: usize) -> (Self, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+
+        (
+            Self {
+                id,
+                sender,
+                echo: true,
+                identity: None,
+                dropped: Arc::default(),
+            },
+            receiver,
+        )
+    }
+}
+
+struct Session<B: Backend> {
+    id: ConnectionId,
+    backend: B,
+    attendee: B::Attendee,
+    directory: Directory,
+    resumptions: Resumptions,
+    /// Parks the session when the socket drops, [None] when resuming is off
+    token: Option<String>,
+    outbox: Outbox,
+    /// See [Subscriber::dropped]
+    dropped: Arc<AtomicU64>,
+    /// The channel from the url the socket connected to, if any
+    channel: Option<String>,
+    /// Every channel and pattern we are subscribed to
+    subscriptions: HashSet<String>,
+    /// How far we got in each channel we are in, going by what was written to the socket
+    cursors: HashMap<String, Cursor>,
+    /// Broadcasts queued in the outbox by the number of their frame, the cursors move
+    /// past them once they are written
+    unwritten: VecDeque<(u64, Arc<protocol::Broadcast>)>,
+    /// What the client didn't acknowledge yet, [None] when it doesn't acknowledge
+    window: Option<Window>,
+}
+
+impl<B: Backend> Session<B> {
+    /// Tell the client how to resume, pick up the subscriptions of the session it
+    /// resumed and join the channel from the url, before anything the client sends is
+    /// handled
+    async fn start(
+        &mut self,
+        resumed: Option<Parked>,
+        replay: Option<Replay>,
+    ) -> Result<(), Error> {
+        if let Some(token) = &self.token {
+            let event = protocol::Event::Session {
+                id: self.id,
+                token,
+                resumed: resumed.is_some(),
+            };
+            self.outbox
+                .push(Payload::Text(event.encode().into()))
+                .await?;
+        }
+        if let Some(parked) = resumed {
+            // Sent before anything newer, the cursors are past them already. They were
+            // let into the window before, so they are kept even when they overflow it.
+            for msg in parked.unacked {
+                if let Some(window) = &mut self.window {
+                    let _ = window.sent(&msg);
+                }
+                self.send(&msg).await?;
+            }
+            for channel in parked.subscriptions {
+                // Whatever came in since we were dropped, patterns have no cursor
+                let missed = parked.cursors.get(&channel).copied().map(Replay::Resume);
+                self.subscribe(channel, missed).await?;
+            }
+        }
+        match self.channel.clone() {
+            Some(channel) => self.subscribe(channel, replay).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn handle_text(&mut self, msg: String) -> Result<(), Error> {
+        match protocol::Command::parse(&msg)? {
+            Some(protocol::Command::Subscribe {
+                channel,
+                history,
+                since,
+            }) => self.subscribe(channel, Replay::new(history, since)).await?,
+            Some(protocol::Command::Unsubscribe { channel }) => self.unsubscribe(&channel)?,
+            Some(protocol::Command::Publish { channel, data }) => {
+                self.publish(&channel, data.into()).await?
+            }
+            Some(protocol::Command::Direct { to, data }) => self.direct(to, &data)?,
+            Some(protocol::Command::Presence { channel }) => self.presence(&channel).await?,
+            Some(protocol::Command::Ack { channel, seq }) => self.ack(&channel, seq),
+            None => match self.channel.clone() {
+                Some(channel) => self.publish(&channel, msg.into()).await?,
+                None => println!("Dropping message, no channel to publish it to"),
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Binary frames can only be published to the channel from the url
+    async fn handle_binary(&mut self, msg: Vec<u8>) -> Result<(), Error> {
+        match self.channel.clone() {
+            Some(channel) => self.publish(&channel, msg.into()).await,
+            None => {
+                println!("Dropping binary message, no channel to publish it to");
+                Ok(())
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, msg: Message) -> Result<(), Error> {
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            println!("Dropped {dropped} messages for a slow client, its mailbox was full");
+            self.outbox.missed(dropped)?;
+        }
+        match msg {
+            Message::Out(msg) => self.out(&msg).await?,
+            Message::Match(msg) => {
+                // Already delivered by the channel when we are in the channel itself
+                if !self.subscriptions.contains(msg.channel()) {
+                    self.outbox.push(Payload::Text(msg.event())).await?;
+                }
+            }
+            Message::Lagged { channel, missed } => {
+                println!("Skipped {missed} messages from {channel} for a slow client");
+                self.outbox.missed(missed)?;
+            }
+            Message::Close(err) => return Err(err),
+            Message::Direct(event) => self.outbox.push(Payload::Text(event)).await?,
+        }
+
+        Ok(())
+    }
+
+    async fn out(&mut self, msg: &Arc<protocol::Broadcast>) -> Result<(), Error> {
+        self.catch_up();
+        // In the window before the cursor moves past it, even when the window is full.
+        // What isn't acknowledged comes again on resume, so the cursor can move right
+        // away, otherwise it waits until the frame is written.
+        let kept = match &mut self.window {
+            Some(window) if msg.seq().is_some() => window.sent(msg),
+            _ => Ok(()),
+        };
+        if self.window.is_some() {
+            self.advance(msg);
+        }
+        kept?;
+        let number = self.send(msg).await?;
+        if let (None, Some(number)) = (&self.window, number) {
+            self.unwritten.push_back((number, msg.clone()));
+        }
+
+        Ok(())
+    }
+
+    fn advance(&mut self, msg: &protocol::Broadcast) {
+        if let (Some(cursor), Some(seq)) = (self.cursors.get_mut(msg.channel()), msg.seq()) {
+            cursor.seq = seq.max(cursor.seq);
+        }
+    }
+
+    /// Move the cursors past every broadcast written to the socket by now
+    fn catch_up(&mut self) {
+        let written = self.outbox.written();
+        while self
+            .unwritten
+            .front()
+            .is_some_and(|(number, _)| *number <= written)
+        {
+            if let Some((_, msg)) = self.unwritten.pop_front() {
+                self.advance(&msg);
+            }
+        }
+    }
+
+    /// Returns the number of the frame, see [Outbox::push_numbered]
+    async fn send(&mut self, msg: &protocol::Broadcast) -> Result<Option<u64>, Error> {
+        // Binary frames can't carry the envelope, they go out as they came in unless
+        // the client has to acknowledge them
+        let frame = match msg.data() {
+            Payload::Binary(data)
+                if self.window.is_none() && self.channel.as_deref() == Some(msg.channel()) =>
+            {
+                Payload::Binary(data)
+            }
+            _ => Payload::Text(msg.event()),
+        };
+        self.outbox.push_numbered(frame).await
+    }
+
+    fn ack(&mut self, channel: &str, seq: u64) {
+        let Some(window) = &mut self.window else {
+            println!("Ignoring ack, the client didn't ask to acknowledge");
+            return;
+        };
+        for latency in window.ack(channel, seq) {
+            self.outbox.acked(latency);
+        }
+    }
+
+    /// Send again whatever wasn't acknowledged in time
+    async fn redeliver(&mut self) -> Result<(), Error> {
+        let overdue = self
+            .window
+            .as_mut()
+            .map(Window::overdue)
+            .unwrap_or_default();
+        if overdue.is_empty() {
+            return Ok(());
+        }
+        println!("Redelivering {} unacknowledged messages", overdue.len());
+        self.outbox.redelivered(overdue.len() as u64);
+        for msg in overdue {
+            self.send(&msg).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn subscribe(&mut self, channel: String, replay: Option<Replay>) -> Result<(), Error> {
+        if topic::is_pattern(&channel) && !topic::is_valid_pattern(&channel) {
+            println!("Ignoring invalid pattern {channel}");
+            return Ok(());
+        }
+        if self.subscriptions.contains(&channel) {
+            return Ok(());
+        }
+
+        let replayed = self
+            .backend
+            .join(&mut self.attendee, &channel, replay)
+            .await?;
+        if !topic::is_pattern(&channel) {
+            // Right before what is replayed, the cursor moves past it as it is written
+            let first = replayed.broadcasts.first().and_then(|msg| msg.seq());
+            let cursor = Cursor {
+                epoch: replayed.epoch,
+                seq: first.map_or(replayed.seq, |seq| seq - 1),
+            };
+            self.cursors.insert(channel.clone(), cursor);
+        }
+        self.subscriptions.insert(channel);
+        // Nothing delivered for the channel is handled before these went out
+        for msg in replayed.broadcasts {
+            self.out(&msg).await?;
+        }
+
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, channel: &str) -> Result<(), Error> {
+        if self.subscriptions.remove(channel) {
+            self.cursors.remove(channel);
+            self.unwritten.retain(|(_, msg)| msg.channel() != channel);
+            if let Some(window) = &mut self.window {
+                window.forget(channel);
+            }
+            self.backend.leave(&mut self.attendee, channel)?;
+        }
+
+        Ok(())
+    }
+
+    async fn publish(&mut self, channel: &str, msg: Payload) -> Result<(), Error> {
+        if topic::is_pattern(channel) {
+            println!("Dropping message, can't publish to pattern {channel}");
+            return Ok(());
+        }
+
+        self.backend.publish(&mut self.attendee, channel, msg).await
+    }
+
+    async fn presence(&mut self, channel: &str) -> Result<(), Error> {
+        let members = self.backend.presence(&mut self.attendee, channel).await?;
+        let event = protocol::Event::Presence {
+            channel,
+            members: &members,
+        };
+        self.outbox.push(Payload::Text(event.encode().into())).await
+    }
+
+    fn direct(&mut self, to: ConnectionId, data: &str) -> Result<(), Error> {
+        let peer = self.directory.get(to).ok_or(Error::UnknownConnection(to))?;
+        match peer.send_message(Message::Direct(protocol::direct(self.id, data))) {
+            // Gone, it just didn't get to unregister yet
+            Err(Error::ActorGone) => Err(Error::UnknownConnection(to)),
+            sent => sent,
+        }
+    }
+
+    /// Deal with a failure according to its [Recovery], returns false when the connection
+    /// had to be closed
+    async fn recover(&mut self, err: Error) -> bool {
+        match err.recovery() {
+            Recovery::Close(code) => self.close_with(code, err),
+            Recovery::Report => {
+                println!("Reporting to client: {err}");
+                let message = err.to_string();
+                let event = protocol::Event::Error { message: &message }.encode();
+                match self.outbox.push(Payload::Text(event.into())).await {
+                    Ok(()) => true,
+                    // Pushing only fails when the connection has to be closed
+                    Err(err) => match err.recovery() {
+                        Recovery::Close(code) => self.close_with(code, err),
+                        _ => true,
+                    },
+                }
+            }
+            _ => {
+                println!("Dropping message: {err}");
+                true
+            }
+        }
+    }
+
+    fn close_with(&mut self, code: u16, err: Error) -> bool {
+        println!("Closing connection: {err}");
+        self.outbox.close(ws::CloseFrame {
+            code,
+            reason: err.to_string().into(),
+        });
+
+        false
+    }
+
+    /// Leave every channel and pattern and let the backend forget about us, the outbox
+    /// is handed back to be flushed. A session whose client is `lost` rather than gone
+    /// is parked for it to resume.
+    fn close(mut self, lost: bool) -> Outbox {
+        self.catch_up();
+        let parked = Parked {
+            id: self.id,
+            subscriptions: self.subscriptions.clone(),
+            cursors: std::mem::take(&mut self.cursors),
+            unacked: self
+                .window
+                .as_ref()
+                .map(Window::unacked)
+                .unwrap_or_default(),
+        };
+        for channel in std::mem::take(&mut self.subscriptions) {
+            if let Err(err) = self.backend.leave(&mut self.attendee, &channel) {
+                println!("Failed to unsubscribe from {channel}: {err}");
+            }
+        }
+        self.backend.disconnect(self.attendee);
+        self.directory.unregister(self.id);
+        // Only once we left, so the resumed session doesn't join before that
+        if let Some(token) = self.token.filter(|_| lost) {
+            println!("Parking connection {} for it to resume", self.id);
+            self.resumptions.park(token, parked);
+        }
+
+        self.outbox
+    }
+}
+
+/// Whether the connection failed because the client went away without closing the
+/// socket
+fn is_lost(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Socket(_) | Error::MissedHeartbeats(_) | Error::Unacked(_)
+    )
+}
+
+/// What the session keeps answering while it waits, e.g. for room in a full outbox
+struct Watch {
+    heartbeat: Heartbeat,
+    shutdown: shutdown::Guard,
+    pinger: Pinger,
+}
+
+impl Watch {
+    /// Resolves once a ping was queued, or fails when the client stopped answering them
+    /// or the server is shutting down
+    async fn next(&mut self) -> Result<(), Error> {
+        tokio::select! {
+            ping = self.heartbeat.tick() => ping.map(|()| self.pinger.ping()),
+            _ = self.shutdown.closing() => Err(Error::ShuttingDown),
+        }
+    }
+
+    /// Runs `work` unless the client stops answering pings or the server shuts down
+    /// first. Pongs aren't read meanwhile, a client that doesn't take a single frame
+    /// for that long is as good as gone.
+    async fn around<T>(
+        &mut self,
+        work: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        tokio::pin!(work);
+        loop {
+            tokio::select! {
+                done = &mut work => return done,
+                next = self.next() => next?,
+            }
+        }
+    }
+}
+
+/// Runs the session until the client goes away or the connection has to be closed,
+/// returns the outbox with whatever is left to write to the client
+#[allow(clippy::too_many_arguments)]
+pub async fn run<B, S>(
+    backend: B,
+    outbox: Outbox,
+    mut socket: S,
+    options: Options,
+    heartbeat: Heartbeat,
+    shutdown: shutdown::Guard,
+    directory: Directory,
+    resumptions: Resumptions,
+    ack: ack::Config,
+) -> Outbox
+where
+    B: Backend,
+    S: Stream<Item = Result<ws::Message, axum::Error>> + Unpin,
+{
+    let resumed = options.resume.as_deref().and_then(|token| {
+        let parked = resumptions.take(token);
+        if parked.is_none() {
+            println!("Can't resume, the session is gone or never was");
+        }
+        parked
+    });
+    let (sender, mut mailbox) = mpsc::channel(MAILBOX_CAPACITY);
+    let dropped = Arc::<AtomicU64>::default();
+    let subscriber = Subscriber {
+        // A resumed connection keeps its id, so direct messages still reach it
+        id: resumed.as_ref().map_or_else(
+            || NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            |parked| parked.id,
+        ),
+        sender,
+        echo: options.echo,
+        identity: options.identity,
+        dropped: dropped.clone(),
+    };
+    let id = subscriber.get_id();
+    let attendee = match backend.connect(subscriber.clone()).await {
+        Ok(attendee) => attendee,
+        Err(err) => {
+            println!("Failed to start connection: {err}");
+            return outbox;
+        }
+    };
+    directory.register(subscriber);
+    let mut session = Session {
+        id,
+        backend,
+        attendee,
+        directory,
+        token: resumptions.issue(),
+        resumptions,
+        outbox,
+        dropped,
+        channel: options.channel,
+        subscriptions: HashSet::new(),
+        cursors: HashMap::new(),
+        unwritten: VecDeque::new(),
+        window: options.ack.then(|| Window::new(ack)),
+    };
+
+    let mut watch = Watch {
+        heartbeat,
+        shutdown,
+        pinger: session.outbox.pinger(),
+    };
+
+    // Every push may wait for room, so everything that pushes runs around the watch
+    let mut handled = watch.around(session.start(resumed, options.replay)).await;
+    // Whether the client went away without closing the socket
+    let mut lost = false;
+    loop {
+        if let Err(err) = handled {
+            lost = is_lost(&err);
+            let open = match watch.around(async { Ok(session.recover(err).await) }).await {
+                Ok(open) => open,
+                // Closing pushes nothing
+                Err(err) => {
+                    lost = is_lost(&err);
+                    session.recover(err).await
+                }
+            };
+            if !open {
+                break;
+            }
+        }
+        handled = tokio::select! {
+            msg = socket.next() => match msg {
+                Some(Ok(ws::Message::Text(msg))) => watch.around(session.handle_text(msg)).await,
+                Some(Ok(ws::Message::Binary(msg))) => {
+                    watch.around(session.handle_binary(msg)).await
+                }
+                Some(Ok(ws::Message::Pong(_))) => {
+                    watch.heartbeat.pong();
+                    Ok(())
+                }
+                Some(Ok(ws::Message::Close(_))) => break,
+                Some(Err(_)) | None => {
+                    lost = true;
+                    break;
+                }
+                Some(Ok(_)) => Ok(()),
+            },
+            Some(msg) = mailbox.recv() => watch.around(session.handle_message(msg)).await,
+            msg = session.backend.deliver(&mut session.attendee) => {
+                watch.around(session.handle_message(msg)).await
+            }
+            () = Window::due(session.window.as_ref()) => watch.around(session.redeliver()).await,
+            next = watch.next() => next,
+        };
+    }
+
+    session.close(lost)
+}
@@ -0,0 +1,158 @@
+//! Graceful shutdown on SIGINT or SIGTERM.
+//!
+//! The server stops accepting connections and every session closes its socket with
+//! 1001 (Going Away), after whatever is still queued for the client. The backend is
+//! stopped once every session is gone, or after [Config::drain_timeout_secs].
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+
+use crate::config;
+
+/// The `[shutdown]` section of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How long to wait for connections to flush their outbox and close
+    pub drain_timeout_secs: u64,
+}
+
+impl Config {
+    /// Overrides the file with `SHUTDOWN_DRAIN_TIMEOUT_SECS`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(secs) = config::env("SHUTDOWN_DRAIN_TIMEOUT_SECS")? {
+            self.drain_timeout_secs = secs;
+        }
+
+        Ok(())
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 10,
+        }
+    }
+}
+
+/// Tells the sessions to close and waits for them to be gone
+pub struct Shutdown {
+    closing: watch::Sender<bool>,
+    /// Every [Guard] holds a clone, so the receiver only ends once they are all dropped
+    sessions: mpsc::Sender<()>,
+    drained: mpsc::Receiver<()>,
+}
+
+/// Held by every session until its outbox is flushed
+#[derive(Clone)]
+pub struct Guard {
+    closing: watch::Receiver<bool>,
+    _session: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (closing, _) = watch::channel(false);
+        let (sessions, drained) = mpsc::channel(1);
+
+        Self {
+            closing,
+            sessions,
+            drained,
+        }
+    }
+
+    pub fn guard(&self) -> Guard {
+        Guard {
+            closing: self.closing.subscribe(),
+            _session: self.sessions.clone(),
+        }
+    }
+
+    /// Tell every session to close its socket
+    pub fn trigger(&self) {
+        self.closing.send_replace(true);
+    }
+
+    /// Wait until every [Guard] is dropped, returns false when `timeout` passed first
+    pub async fn drain(self, timeout: Duration) -> bool {
+        let Self {
+            sessions,
+            mut drained,
+            ..
+        } = self;
+        drop(sessions);
+
+        tokio::time::timeout(timeout, drained.recv()).await.is_ok()
+    }
+}
+
+impl Guard {
+    /// Resolves once the server is shutting down
+    pub async fn closing(&mut self) {
+        if self.closing.wait_for(|closing| *closing).await.is_err() {
+            // The server went away without shutting down, there is nothing to wait for
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_every_guard() {
+        let shutdown = Shutdown::new();
+        let mut guard = shutdown.guard();
+        let closed = tokio::spawn(async move {
+            guard.closing().await;
+        });
+
+        shutdown.trigger();
+        closed.await.unwrap();
+
+        assert!(shutdown.drain(Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_the_timeout() {
+        let shutdown = Shutdown::new();
+        let _guard = shutdown.guard();
+        shutdown.trigger();
+
+        assert!(!shutdown.drain(Duration::from_millis(10)).await);
+    }
+}
@@ -1,66 +1,290 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
 
-use super::connection;
+use crate::error::{Error, Recovery};
+use crate::history::{Appended, History, Replay, Replayed};
+use crate::presence::{Member, Members};
+use crate::protocol::{Broadcast, Publication};
+use crate::server::session::{self, ConnectionId};
+use crate::server::Subscriber;
+use crate::topic::TopicIndex;
 use tokio::sync::mpsc;
 
+/// Pattern subscriptions of every channel, written by the registry and read on publish
+pub type Topics = Arc<RwLock<TopicIndex<ConnectionId, Subscriber>>>;
+
+/// A channel split into shards, each one an actor of its own holding the attendies
+/// whose connection id falls on it. A broadcast goes to every shard, so the fan out
+/// runs on as many cores as there are shards. A shard's queues are never full, so no
+/// broadcast that was given a sequence number is ever dropped on the way to it. Joins
+/// and leaves go on a queue of their own, which the shard takes first.
 #[derive(Clone)]
 pub struct ChannelActorHandle {
-    sender: mpsc::Sender<ActorMessage>,
+    name: Arc<str>,
+    shards: Arc<[mpsc::UnboundedSender<ActorMessage>]>,
+    /// The joins and leaves of every shard
+    seats: Arc<[mpsc::UnboundedSender<Seat>]>,
+    /// Held while a broadcast is handed to the shards, so they all get the broadcasts in
+    /// the same order
+    ledger: Arc<Mutex<Ledger>>,
+}
+
+/// What the shards of a channel share
+struct Ledger {
+    members: Members,
+    history: History,
 }
 
 impl ChannelActorHandle {
-    pub fn new() -> Self {
-        let (sender, receiver) = mpsc::channel(500);
-        let actor = ChannelActor::new(receiver);
-        tokio::spawn(run(actor));
+    /// Carries on after the last broadcast in its history
+    pub fn new(name: String, topics: Topics, shards: NonZeroUsize, history: History) -> Self {
+        let (shards, seats) = (0..shards.get())
+            .map(|shard| {
+                let (sender, receiver) = mpsc::unbounded_channel();
+                let (seats, seated) = mpsc::unbounded_channel();
+                // Pattern subscribers are only reached from the first shard
+                let topics = (shard == 0).then(|| topics.clone());
+                let actor = ChannelActor::new(receiver, seated, name.clone(), topics);
+                tokio::spawn(run(actor));
+                (sender, seats)
+            })
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        let ledger = Ledger {
+            members: Members::new(&name),
+            history,
+        };
+
+        Self {
+            name: name.into(),
+            shards: shards.into(),
+            seats: seats.into(),
+            ledger: Arc::new(Mutex::new(ledger)),
+        }
+    }
+
+    fn ledger(&self) -> MutexGuard<'_, Ledger> {
+        self.ledger.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hand the join or leave to the shard of the connection
+    fn seat(&self, seat: Seat) -> Result<(), Error> {
+        let conn = match &seat {
+            Seat::Join { conn, .. } | Seat::Leave(conn) => conn,
+        };
+        let shard = conn.get_id() as usize % self.seats.len();
+        self.seats[shard].send(seat)?;
+
+        Ok(())
+    }
+
+    /// Hand the message to every shard
+    pub fn send_message(&self, msg: ActorMessage) -> Result<(), Error> {
+        // A stopped shard only misses this one, the others still get it
+        let mut sent = Ok(());
+        for shard in self.shards.iter() {
+            if let Err(err) = shard.send(msg.clone()) {
+                sent = Err(err.into());
+            }
+        }
+        sent
+    }
+
+    /// Stamp the broadcast once right away, and hand it to every shard once the journal
+    /// wrote it. The future resolves once it was handed over.
+    pub fn publish(&self, msg: Publication) -> impl Future<Output = Result<(), Error>> {
+        let mut ledger = self.ledger();
+        let appended = match ledger.history.append(&self.name, msg).try_written() {
+            Ok(appended) => Ok(self.release(&mut ledger, appended)),
+            Err(appending) => Err(appending),
+        };
+        drop(ledger);
+
+        let channel = self.clone();
+        async move {
+            match appended {
+                Ok(sent) => sent,
+                Err(appending) => {
+                    let appended = appending.written().await;
+                    channel.release(&mut channel.ledger(), appended)
+                }
+            }
+        }
+    }
+
+    /// Hand every broadcast the history released to the shards, under the ledger lock
+    fn release(&self, ledger: &mut Ledger, appended: Appended) -> Result<(), Error> {
+        for msg in ledger.history.release(&appended) {
+            self.send_message(ActorMessage::Message(msg))?;
+        }
+        appended.result
+    }
 
-        Self { sender }
+    /// Add the connection to its shard and tell everybody when its user wasn't in the
+    /// channel yet. Returns what `replay` asks for and the sequence number the channel is
+    /// at, the shard delivers everything after.
+    pub fn join(&self, conn: Subscriber, replay: Option<Replay>) -> Result<Replayed, Error> {
+        let identity = conn.identity().cloned();
+        let id = conn.get_id();
+        // No broadcast is released between the join and the replay
+        let mut ledger = self.ledger();
+        self.seat(Seat::Join {
+            conn,
+            seq: ledger.history.seq(),
+        })?;
+        let replayed = Replayed {
+            epoch: ledger.history.epoch(),
+            seq: ledger.history.seq(),
+            broadcasts: replay.map_or_else(Vec::new, |replay| ledger.history.replay(replay)),
+        };
+
+        // The connection is in either way, a stopped shard only misses the notice
+        if let Some(notice) = identity.and_then(|identity| ledger.members.join(id, &identity)) {
+            if let Err(err) = self.send_message(ActorMessage::Notice(notice)) {
+                println!("Dropping presence notice for channel {}: {err}", self.name);
+            }
+        }
+
+        Ok(replayed)
     }
 
-    pub fn send_message(
-        &self,
-        msg: ActorMessage,
-    ) -> Result<(), mpsc::error::TrySendError<ActorMessage>> {
-        self.sender.try_send(msg)
+    /// Take the connection off its shard and tell everybody else when it was the last
+    /// one of its user
+    pub fn leave(&self, conn: Subscriber) -> Result<(), Error> {
+        let identity = conn.identity().cloned();
+        let id = conn.get_id();
+        // The members are updated even when the shard is gone
+        let left = self.seat(Seat::Leave(conn));
+
+        let Some(identity) = identity else {
+            return left;
+        };
+        let mut ledger = self.ledger();
+        if let Some(notice) = ledger.members.leave(id, &identity) {
+            self.send_message(ActorMessage::Notice(notice))?;
+        }
+
+        left
+    }
+
+    pub fn presence(&self) -> Vec<Member> {
+        self.ledger().members.list()
+    }
+
+    /// Stop every shard once the messages already queued have been handled
+    pub fn stop(&self) {
+        for shard in self.shards.iter() {
+            let _ = shard.send(ActorMessage::Stop);
+        }
     }
 }
 
 pub struct ChannelState {
-    attendies: HashMap<i32, connection::ConnectionActorHandle>,
+    name: String,
+    /// Every connection on the shard, with the sequence number it joined at
+    attendies: HashMap<ConnectionId, (Subscriber, u64)>,
+    /// Only set on the shard that delivers to pattern subscribers
+    topics: Option<Topics>,
 }
 
 pub struct ChannelActor {
-    receiver: mpsc::Receiver<ActorMessage>,
+    receiver: mpsc::UnboundedReceiver<ActorMessage>,
+    seated: mpsc::UnboundedReceiver<Seat>,
     state: ChannelState,
 }
 
+/// Joins and leaves are taken before any message, a join may get ahead of broadcasts
+/// that were replayed to the connection already
+pub enum Seat {
+    /// Deliver every broadcast after `seq` to the connection
+    Join {
+        conn: Subscriber,
+        seq: u64,
+    },
+    Leave(Subscriber),
+}
+
+#[derive(Clone)]
 pub enum ActorMessage {
-    Join(connection::ConnectionActorHandle),
-    Leave(connection::ConnectionActorHandle),
-    Message(String),
+    Message(Arc<Broadcast>),
+    /// A broadcast from the server, only for the connections in the channel
+    Notice(Arc<Broadcast>),
+    /// Stop the actor once the messages already queued have been handled
+    Stop,
 }
 
 impl ChannelActor {
-    fn new(receiver: mpsc::Receiver<ActorMessage>) -> Self {
+    fn new(
+        receiver: mpsc::UnboundedReceiver<ActorMessage>,
+        seated: mpsc::UnboundedReceiver<Seat>,
+        name: String,
+        topics: Option<Topics>,
+    ) -> Self {
         Self {
             receiver,
+            seated,
             state: ChannelState {
+                name,
                 attendies: HashMap::new(),
+                topics,
             },
         }
     }
-    fn handle_message(&mut self, msg: ActorMessage) {
-        match msg {
-            ActorMessage::Join(conn) => {
-                self.state.attendies.insert(conn.get_id(), conn);
+    fn handle_seat(&mut self, seat: Seat) {
+        match seat {
+            Seat::Join { conn, seq } => {
+                self.state.attendies.insert(conn.get_id(), (conn, seq));
             }
-            ActorMessage::Leave(conn) => {
+            Seat::Leave(conn) => {
                 self.state.attendies.remove(&conn.get_id());
             }
+        }
+    }
+    fn handle_message(&mut self, msg: ActorMessage) {
+        match msg {
+            ActorMessage::Notice(msg) => self.fan_out(&msg),
             ActorMessage::Message(msg) => {
-                for (_id, conn) in self.state.attendies.clone() {
-                    conn.send_message(connection::ActorMessage::Out(msg.clone()))
-                        .unwrap();
+                self.fan_out(&msg);
+
+                // Connections subscribed through a pattern are reached directly, a
+                // connection matching several patterns only shows up once. They are
+                // dropped from the index when they unsubscribe on close.
+                let Some(topics) = &self.state.topics else {
+                    return;
+                };
+                let matched = topics
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .matches(&self.state.name);
+                for (id, conn) in matched {
+                    if !conn.wants(&msg) {
+                        continue;
+                    }
+                    if let Err(err) = conn.send_message(session::Message::Match(msg.clone())) {
+                        println!("Dropping message for {id}: {err}");
+                    }
+                }
+            }
+            ActorMessage::Stop => {
+                self.receiver.close();
+            }
+        }
+    }
+
+    fn fan_out(&mut self, msg: &Arc<Broadcast>) {
+        for (id, (conn, joined)) in self.state.attendies.clone() {
+            let replayed = msg.seq().is_some_and(|seq| seq <= joined);
+            if replayed || !conn.wants(msg) {
+                continue;
+            }
+            if let Err(err) = conn.send_message(session::Message::Out(msg.clone())) {
+                if err.recovery() == Recovery::DropSubscriber {
+                    println!("Connection Closed");
+                    self.state.attendies.remove(&id);
+                } else {
+                    println!("Dropping message for {id}: {err}");
                 }
             }
         }
@@ -68,7 +292,223 @@ impl ChannelActor {
 }
 
 async fn run(mut actor: ChannelActor) {
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg);
+    loop {
+        tokio::select! {
+            biased;
+            Some(seat) = actor.seated.recv() => actor.handle_seat(seat),
+            msg = actor.receiver.recv() => match msg {
+                Some(msg) => actor.handle_message(msg),
+                None => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Payload;
+
+    fn channel_actor() -> ChannelActor {
+        let (_sender, receiver) = mpsc::unbounded_channel();
+        let (_seats, seated) = mpsc::unbounded_channel();
+        ChannelActor::new(
+            receiver,
+            seated,
+            "room".to_string(),
+            Some(Topics::default()),
+        )
+    }
+
+    fn join(conn: Subscriber) -> Seat {
+        Seat::Join { conn, seq: 0 }
+    }
+
+    fn message(msg: &str) -> ActorMessage {
+        ActorMessage::Message(Broadcast::new(
+            "room",
+            None,
+            Publication::new(1, msg.into()),
+        ))
+    }
+
+    fn delivered(mailbox: &mut mpsc::Receiver<session::Message>) -> Vec<String> {
+        delivered_with_seq(mailbox)
+            .into_iter()
+            .map(|(msg, _)| msg)
+            .collect()
+    }
+
+    fn delivered_with_seq(
+        mailbox: &mut mpsc::Receiver<session::Message>,
+    ) -> Vec<(String, Option<u64>)> {
+        let mut messages = Vec::new();
+        while let Ok(msg) = mailbox.try_recv() {
+            if let session::Message::Out(msg) | session::Message::Match(msg) = msg {
+                if let Payload::Text(text) = msg.data() {
+                    messages.push((text.to_string(), msg.seq()));
+                }
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn gone_subscriber_is_dropped() {
+        let mut actor = channel_actor();
+        let (live, mut live_mailbox) = Subscriber::probe(1, 10);
+        let (gone, gone_mailbox) = Subscriber::probe(2, 10);
+        drop(gone_mailbox);
+        actor.handle_seat(join(live));
+        actor.handle_seat(join(gone));
+
+        actor.handle_message(message("hello"));
+
+        assert_eq!(delivered(&mut live_mailbox), vec!["hello"]);
+        assert!(actor.state.attendies.contains_key(&1));
+        assert!(!actor.state.attendies.contains_key(&2));
+    }
+
+    #[test]
+    fn full_subscriber_misses_the_message_but_stays() {
+        let mut actor = channel_actor();
+        let (live, mut live_mailbox) = Subscriber::probe(1, 10);
+        let (full, mut full_mailbox) = Subscriber::probe(2, 1);
+        actor.handle_seat(join(live));
+        actor.handle_seat(join(full));
+
+        actor.handle_message(message("first"));
+        actor.handle_message(message("second"));
+
+        assert_eq!(delivered(&mut live_mailbox), vec!["first", "second"]);
+        assert_eq!(delivered(&mut full_mailbox), vec!["first"]);
+        assert!(actor.state.attendies.contains_key(&2));
+
+        actor.handle_message(message("third"));
+        assert_eq!(delivered(&mut full_mailbox), vec!["third"]);
+    }
+
+    #[test]
+    fn gone_pattern_subscriber_does_not_stop_the_broadcast() {
+        let mut actor = channel_actor();
+        let (live, mut live_mailbox) = Subscriber::probe(1, 10);
+        let (gone, gone_mailbox) = Subscriber::probe(2, 10);
+        drop(gone_mailbox);
+        let topics = actor.state.topics.as_ref().unwrap();
+        topics.write().unwrap().insert("*", 2, gone);
+        actor.handle_seat(join(live));
+
+        actor.handle_message(message("hello"));
+
+        assert_eq!(delivered(&mut live_mailbox), vec!["hello"]);
+    }
+
+    #[tokio::test]
+    async fn shards_deliver_every_broadcast_once() {
+        let topics = Topics::default();
+        let channel = ChannelActorHandle::new(
+            "room".to_string(),
+            topics.clone(),
+            3.try_into().unwrap(),
+            History::new(0),
+        );
+        let mut mailboxes = Vec::new();
+        for id in 1..=4 {
+            let (conn, mailbox) = Subscriber::probe(id, 10);
+            channel.join(conn, None).unwrap();
+            mailboxes.push(mailbox);
+        }
+        let (pattern, mut pattern_mailbox) = Subscriber::probe(5, 10);
+        topics.write().unwrap().insert("*", 5, pattern);
+
+        channel
+            .publish(Publication::new(1, "hello".into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        for mailbox in &mut mailboxes {
+            assert_eq!(delivered(mailbox), vec!["hello"]);
+        }
+        assert_eq!(delivered(&mut pattern_mailbox), vec!["hello"]);
+    }
+
+    #[tokio::test]
+    async fn shards_agree_on_the_sequence() {
+        let channel = ChannelActorHandle::new(
+            "room".to_string(),
+            Topics::default(),
+            2.try_into().unwrap(),
+            History::new(0),
+        );
+        let mut mailboxes = Vec::new();
+        for id in 1..=2 {
+            let (conn, mailbox) = Subscriber::probe(id, 10);
+            channel.join(conn, None).unwrap();
+            mailboxes.push(mailbox);
+        }
+
+        // Publishers share the channel, a clone stamps from the same sequence
+        let other = channel.clone();
+        channel
+            .publish(Publication::new(1, "a".into()))
+            .await
+            .unwrap();
+        other
+            .publish(Publication::new(2, "b".into()))
+            .await
+            .unwrap();
+        channel
+            .publish(Publication::new(1, "c".into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        for mailbox in &mut mailboxes {
+            assert_eq!(
+                delivered_with_seq(mailbox),
+                vec![
+                    ("a".to_string(), Some(1)),
+                    ("b".to_string(), Some(2)),
+                    ("c".to_string(), Some(3)),
+                ]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn queued_broadcasts_all_arrive_and_joins_get_ahead_of_them() {
+        let channel = ChannelActorHandle::new(
+            "room".to_string(),
+            Topics::default(),
+            1.try_into().unwrap(),
+            History::new(10),
+        );
+        let (early, mut early_mailbox) = Subscriber::probe(1, 2000);
+        channel.join(early, None).unwrap();
+        // The shard doesn't get to run before we wait, they all queue up
+        for seq in 1..=1000 {
+            channel
+                .publish(Publication::new(1, seq.to_string().into()))
+                .await
+                .unwrap();
+        }
+        let (conn, mut mailbox) = Subscriber::probe(2, 10);
+
+        // The shard takes the join first and skips what was replayed
+        let replayed = channel.join(conn, Some(Replay::Last(2))).unwrap();
+        channel
+            .publish(Publication::new(1, "after".into()))
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(replayed.seq, 1000);
+        assert_eq!(replayed.broadcasts.len(), 2);
+        assert_eq!(
+            delivered_with_seq(&mut mailbox),
+            vec![("after".to_string(), Some(1001))]
+        );
+        assert_eq!(delivered(&mut early_mailbox).len(), 1001);
     }
 }
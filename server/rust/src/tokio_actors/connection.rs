@@ -1,102 +1,83 @@
+use std::collections::HashMap;
+
 use super::channel;
-use axum::extract::ws::{self, WebSocket};
-use futures::SinkExt;
-use futures_util::stream::SplitSink;
-use rand::Rng;
-use tokio::sync::mpsc;
+use super::registry;
+use crate::error::Error;
+use crate::history::{Replay, Replayed};
+use crate::protocol::{Payload, Publication};
+use crate::server::Subscriber;
+use crate::topic;
 
-#[derive(Clone)]
-pub struct ConnectionActorHandle {
-    id: i32,
-    sender: mpsc::Sender<ActorMessage>,
+/// What this backend keeps for every connection
+pub struct Attendee {
+    pub subscriber: Subscriber,
+    /// Every channel we are subscribed to
+    pub memberships: HashMap<String, channel::ChannelActorHandle>,
 }
 
-impl ConnectionActorHandle {
-    pub fn new(state: ConnectionState) -> Self {
-        let mut rng = rand::thread_rng();
-        let (sender, receiver) = mpsc::channel(500);
-
-        let handler = Self {
-            id: rng.gen(),
-            sender,
-        };
-
-        state
-            .channel_actor
-            .send_message(channel::ActorMessage::Join(handler.clone()))
-            .unwrap();
-
-        let actor = ConnectionActor::new(receiver, state, handler.clone());
-        tokio::spawn(run(actor));
-
-        handler
-    }
-    pub fn get_id(self: &Self) -> i32 {
-        self.id
+impl Attendee {
+    pub fn new(subscriber: Subscriber) -> Self {
+        Self {
+            subscriber,
+            memberships: HashMap::new(),
+        }
     }
 
-    pub fn send_message(
-        &self,
-        msg: ActorMessage,
-    ) -> Result<(), mpsc::error::TrySendError<ActorMessage>> {
-        self.sender.try_send(msg)
-    }
-}
+    pub async fn subscribe(
+        &mut self,
+        registry_actor: &registry::RegistryActorHandle,
+        channel: &str,
+        replay: Option<Replay>,
+    ) -> Result<Replayed, Error> {
+        if topic::is_pattern(channel) {
+            registry_actor.subscribe_pattern(channel, self.subscriber.clone());
+            return Ok(Replayed::default());
+        }
 
-pub struct ConnectionState {
-    pub ws: SplitSink<WebSocket, ws::Message>,
-    pub channel_actor: channel::ChannelActorHandle,
-}
-
-struct ConnectionActor {
-    receiver: mpsc::Receiver<ActorMessage>,
-    state: ConnectionState,
-    handler: ConnectionActorHandle,
-}
-
-#[derive(Debug)]
-pub enum ActorMessage {
-    In(String),
-    Out(String),
-    Close,
-}
-
-impl ConnectionActor {
-    fn new(
-        receiver: mpsc::Receiver<ActorMessage>,
-        state: ConnectionState,
-        handler: ConnectionActorHandle,
-    ) -> Self {
-        let actor = Self {
-            receiver,
-            state,
-            handler,
+        let channel_actor = registry_actor.join(channel.to_string()).await?;
+        let replayed = match channel_actor.join(self.subscriber.clone(), replay) {
+            Ok(replayed) => replayed,
+            Err(err) => {
+                // Give the seat back
+                registry_actor.leave(channel.to_string());
+                return Err(err);
+            }
         };
+        self.memberships.insert(channel.to_string(), channel_actor);
 
-        actor
+        Ok(replayed)
     }
-    async fn handle_message(&mut self, msg: ActorMessage) {
-        let channel_actor = self.state.channel_actor.clone();
-        match msg {
-            ActorMessage::In(msg) => {
-                channel_actor
-                    .send_message(channel::ActorMessage::Message(msg))
-                    .unwrap();
-            }
-            ActorMessage::Out(msg) => {
-                self.state.ws.send(ws::Message::Text(msg)).await.unwrap();
-            }
-            ActorMessage::Close => {
-                channel_actor
-                    .send_message(channel::ActorMessage::Leave(self.handler.clone()))
-                    .unwrap();
-            }
+
+    pub fn unsubscribe(
+        &mut self,
+        registry_actor: &registry::RegistryActorHandle,
+        channel: &str,
+    ) -> Result<(), Error> {
+        if topic::is_pattern(channel) {
+            registry_actor.unsubscribe_pattern(channel, self.subscriber.get_id());
+            return Ok(());
+        }
+        let Some(channel_actor) = self.memberships.remove(channel) else {
+            return Ok(());
         };
+
+        // Hand the seat back even when the channel is gone
+        let left = channel_actor.leave(self.subscriber.clone());
+        registry_actor.leave(channel.to_string());
+
+        left
     }
-}
 
-async fn run(mut actor: ConnectionActor) {
-    while let Some(msg) = actor.receiver.recv().await {
-        actor.handle_message(msg).await;
+    pub async fn publish(
+        &self,
+        registry_actor: &registry::RegistryActorHandle,
+        channel: &str,
+        msg: Payload,
+    ) -> Result<(), Error> {
+        let msg = Publication::new(self.subscriber.get_id(), msg);
+        match self.memberships.get(channel) {
+            Some(channel_actor) => channel_actor.publish(msg).await,
+            None => registry_actor.publish(channel.to_string(), msg).await,
+        }
     }
 }
@@ -1,124 +1,105 @@
 mod channel;
 mod connection;
+mod registry;
 
-use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::State,
-    response::IntoResponse,
-    routing::get,
-    Router,
-};
-use axum_extra::TypedHeader;
-
-use std::net::SocketAddr;
-use tower_http::trace::{DefaultMakeSpan, TraceLayer};
-
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-//allows to extract the IP of connecting user
-use axum::extract::connect_info::ConnectInfo;
-
-//allows to split the websocket stream into separate TX and RX branches
-use futures::stream::StreamExt;
-
-pub async fn run() {
-    let channel_handler = channel::ChannelActorHandle::new();
-
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "example_websockets=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    // build our application with some routes
-    let app = Router::new()
-        .route("/global", get(ws_handler))
-        // logging so we can see whats going on
-        .with_state(channel_handler)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::default().include_headers(true)),
-        );
-
-    // run it with hyper
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:8888")
-        .await
-        .unwrap();
-    tracing::debug!("listening on {}", listener.local_addr().unwrap());
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+use std::num::NonZeroUsize;
+
+use serde::Deserialize;
+
+use crate::config;
+use crate::error::Error;
+use crate::history::{Replay, Replayed};
+use crate::presence::Member;
+use crate::protocol::Payload;
+use crate::server::{Backend, Subscriber};
+
+/// The `[channel]` section of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How many tasks every channel fans out on, one keeps the whole channel on one task.
+    /// One is the default: more shards only pay off on cores to spare for them, on a
+    /// single core they cost throughput.
+    pub shards: NonZeroUsize,
 }
 
-/// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
-/// of websocket negotiation). After this completes, the actual switching from HTTP to
-/// websocket protocol will occur.
-/// This is the last point where we can extract TCP/IP metadata such as IP address of the client
-/// as well as things from HTTP headers such as user-agent of the browser etc.
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    user_agent: Option<TypedHeader<headers::UserAgent>>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(channel_handler): State<channel::ChannelActorHandle>,
-) -> impl IntoResponse {
-    let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
-        user_agent.to_string()
-    } else {
-        String::from("Unknown browser")
-    };
-    println!("`{user_agent}` at {addr} connected.");
-    // finalize the upgrade process by returning upgrade callback.
-    // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, channel_handler))
+impl Config {
+    /// Overrides the file with `CHANNEL_SHARDS`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(shards) = config::env("CHANNEL_SHARDS")? {
+            self.shards = shards;
+        }
+
+        Ok(())
+    }
 }
 
-/// Actual websocket statemachine (one will be spawned per connection)
-async fn handle_socket(
-    socket: WebSocket,
-    who: SocketAddr,
-    channel_handler: channel::ChannelActorHandle,
-) {
-    // By splitting socket we can send and receive at the same time. In this example we will send
-    // unsolicited messages to client based on some sort of server's internal event (i.e .timer).
-    let (sender, mut receiver) = socket.split();
-    let connection_state = connection::ConnectionState {
-        ws: sender,
-        channel_actor: channel_handler,
-    };
-
-    let connection_handler = connection::ConnectionActorHandle::new(connection_state);
-
-    // This second task will receive messages from client and print them on server console
-    let conn_handler_ref = connection_handler.clone();
-    let mut recv_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            match msg {
-                Message::Text(msg) => {
-                    conn_handler_ref
-                        .send_message(connection::ActorMessage::In(msg))
-                        .unwrap();
-                }
-                _ => (),
-            }
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            shards: NonZeroUsize::MIN,
         }
-    });
-
-    // If any one of the tasks exit, abort the other.
-    tokio::select! {
-        rv_b = (&mut recv_task) => {
-            match rv_b {
-                Ok(_) => println!("Connection done"),
-                Err(b) => println!("Error receiving messages {b:?}")
-            }
+    }
+}
+
+/// Every channel is a plain tokio task that sends to each connection itself, or several
+/// of them splitting the connections between them, see [Config::shards]
+#[derive(Clone)]
+pub struct TokioActors {
+    registry_actor: registry::RegistryActorHandle,
+}
+
+impl Backend for TokioActors {
+    type Attendee = connection::Attendee;
+
+    async fn start(config: &config::Config) -> Self {
+        Self {
+            registry_actor: registry::RegistryActorHandle::new(
+                config.channel.shards,
+                config.history.clone(),
+            ),
         }
     }
 
-    // returning from the handler closes the websocket connection
-    println!("Websocket context {who} destroyed");
-    connection_handler.send_message(connection::ActorMessage::Close).unwrap();
+    async fn connect(&self, subscriber: Subscriber) -> Result<Self::Attendee, Error> {
+        Ok(connection::Attendee::new(subscriber))
+    }
+
+    async fn join(
+        &self,
+        attendee: &mut Self::Attendee,
+        channel: &str,
+        replay: Option<Replay>,
+    ) -> Result<Replayed, Error> {
+        attendee
+            .subscribe(&self.registry_actor, channel, replay)
+            .await
+    }
+
+    fn leave(&self, attendee: &mut Self::Attendee, channel: &str) -> Result<(), Error> {
+        attendee.unsubscribe(&self.registry_actor, channel)
+    }
+
+    async fn presence(
+        &self,
+        _attendee: &mut Self::Attendee,
+        channel: &str,
+    ) -> Result<Vec<Member>, Error> {
+        self.registry_actor.presence(channel.to_string()).await
+    }
+
+    async fn publish(
+        &self,
+        attendee: &mut Self::Attendee,
+        channel: &str,
+        msg: Payload,
+    ) -> Result<(), Error> {
+        attendee.publish(&self.registry_actor, channel, msg).await
+    }
+
+    fn disconnect(&self, _attendee: Self::Attendee) {}
+
+    async fn stop(&self) {
+        self.registry_actor.stop().await;
+    }
 }
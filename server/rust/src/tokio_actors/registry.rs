@@ -0,0 +1,345 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::PoisonError;
+
+use super::channel;
+use crate::error::Error;
+use crate::history::{self, History};
+use crate::presence::Member;
+use crate::protocol::{Broadcast, Publication};
+use crate::server::session::{self, ConnectionId};
+use crate::server::Subscriber;
+use tokio::sync::{mpsc, oneshot};
+
+#[derive(Clone)]
+pub struct RegistryActorHandle {
+    sender: mpsc::Sender<ActorMessage>,
+    /// Whether channels keep a journal, publishing only waits for it to be written then
+    journaled: bool,
+    /// Shared with the registry, pattern subscriptions are written to it right away so
+    /// none is lost to a full mailbox
+    topics: channel::Topics,
+}
+
+impl RegistryActorHandle {
+    pub fn new(shards: NonZeroUsize, history: history::Config) -> Self {
+        let journaled = history.journal.dir.is_some();
+        let topics = channel::Topics::default();
+        let (sender, receiver) = mpsc::channel(500);
+        let actor = RegistryActor::new(
+            receiver,
+            sender.downgrade(),
+            topics.clone(),
+            shards,
+            history,
+        );
+        tokio::spawn(run(actor));
+
+        Self {
+            sender,
+            journaled,
+            topics,
+        }
+    }
+
+    /// Reserve a seat in the named channel, spawning it if needed. Fails when the
+    /// channel can't open its journal.
+    pub async fn join(&self, name: String) -> Result<channel::ChannelActorHandle, Error> {
+        let (respond_to, response) = oneshot::channel();
+        self.sender
+            .send(ActorMessage::Join {
+                name: name.clone(),
+                respond_to,
+            })
+            .await?;
+
+        response
+            .await
+            .map_err(|_| Error::ChannelUnavailable(name))?
+    }
+
+    /// Who is in the named channel, nobody when it isn't running
+    pub async fn presence(&self, name: String) -> Result<Vec<Member>, Error> {
+        let (respond_to, response) = oneshot::channel();
+        self.sender
+            .send(ActorMessage::Presence {
+                name: name.clone(),
+                respond_to,
+            })
+            .await?;
+
+        response.await.map_err(|_| Error::ChannelUnavailable(name))
+    }
+
+    /// Give a seat in the named channel back, waiting for room in the mailbox when it is
+    /// full so the channel still stops with its last connection
+    pub fn leave(&self, name: String) {
+        if let Err(mpsc::error::TrySendError::Full(msg)) =
+            self.sender.try_send(ActorMessage::Leave(name))
+        {
+            let sender = self.sender.clone();
+            tokio::spawn(async move {
+                let _ = sender.send(msg).await;
+            });
+        }
+    }
+
+    /// Publish to the named channel without holding a seat in it, see
+    /// [ActorMessage::Publish]. Waits for the journal to write the broadcast when there is
+    /// one.
+    pub async fn publish(&self, name: String, msg: Publication) -> Result<(), Error> {
+        let (respond_to, response) = oneshot::channel();
+        self.send_message(ActorMessage::Publish {
+            name: name.clone(),
+            msg,
+            respond_to,
+        })?;
+        if !self.journaled {
+            return Ok(());
+        }
+
+        response
+            .await
+            .map_err(|_| Error::ChannelUnavailable(name))?
+    }
+
+    /// Deliver every channel whose name matches the pattern to the connection
+    pub fn subscribe_pattern(&self, pattern: &str, conn: Subscriber) {
+        self.topics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(pattern, conn.get_id(), conn);
+    }
+
+    pub fn unsubscribe_pattern(&self, pattern: &str, id: ConnectionId) {
+        self.topics
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(pattern, &id);
+    }
+
+    pub fn send_message(&self, msg: ActorMessage) -> Result<(), Error> {
+        self.sender.try_send(msg)?;
+
+        Ok(())
+    }
+
+    /// Stop every channel, then the registry, once the messages already queued have
+    /// been handled
+    pub async fn stop(&self) {
+        let _ = self.sender.send(ActorMessage::Stop).await;
+    }
+}
+
+/// A running channel and the number of connections holding a seat in it
+struct ChannelEntry {
+    channel: channel::ChannelActorHandle,
+    attendies: usize,
+}
+
+pub struct RegistryState {
+    channels: HashMap<String, ChannelEntry>,
+    /// Who is waiting for a seat in every channel whose history is being opened
+    opening: HashMap<String, Vec<oneshot::Sender<Result<channel::ChannelActorHandle, Error>>>>,
+    topics: channel::Topics,
+    /// How many shards every channel is split into
+    shards: NonZeroUsize,
+    history: history::Config,
+}
+
+pub struct RegistryActor {
+    receiver: mpsc::Receiver<ActorMessage>,
+    /// To hand ourselves the histories opened meanwhile
+    sender: mpsc::WeakSender<ActorMessage>,
+    state: RegistryState,
+}
+
+pub enum ActorMessage {
+    Join {
+        name: String,
+        respond_to: oneshot::Sender<Result<channel::ChannelActorHandle, Error>>,
+    },
+    /// The history of a channel somebody is waiting to join, opened off the registry
+    /// as it reads the journal
+    Opened {
+        name: String,
+        opened: Result<History, Error>,
+    },
+    /// Give a seat in the named channel back, stopping it when it was the last one.
+    Leave(String),
+    /// Publish to the named channel without holding a seat in it, only pattern
+    /// subscribers see it when nobody is in the channel. Answered once it was handed to
+    /// the channel.
+    Publish {
+        name: String,
+        msg: Publication,
+        respond_to: oneshot::Sender<Result<(), Error>>,
+    },
+    Presence {
+        name: String,
+        respond_to: oneshot::Sender<Vec<Member>>,
+    },
+    /// Stop every channel and then the registry itself
+    Stop,
+}
+
+impl RegistryActor {
+    fn new(
+        receiver: mpsc::Receiver<ActorMessage>,
+        sender: mpsc::WeakSender<ActorMessage>,
+        topics: channel::Topics,
+        shards: NonZeroUsize,
+        history: history::Config,
+    ) -> Self {
+        Self {
+            receiver,
+            sender,
+            state: RegistryState {
+                channels: HashMap::new(),
+                opening: HashMap::new(),
+                topics,
+                shards,
+                history,
+            },
+        }
+    }
+    fn handle_message(&mut self, msg: ActorMessage) {
+        match msg {
+            ActorMessage::Join { name, respond_to } => {
+                if self.state.channels.contains_key(&name) {
+                    self.seat(&name, respond_to);
+                    return;
+                }
+                match self.state.opening.entry(name.clone()) {
+                    Entry::Occupied(mut entry) => entry.get_mut().push(respond_to),
+                    Entry::Vacant(entry) => {
+                        entry.insert(vec![respond_to]);
+                        let history = self.state.history.clone();
+                        let sender = self.sender.clone();
+                        tokio::spawn(async move {
+                            let opened = History::open(&history, &name).await;
+                            if let Some(sender) = sender.upgrade() {
+                                let _ = sender.send(ActorMessage::Opened { name, opened }).await;
+                            }
+                        });
+                    }
+                }
+            }
+            ActorMessage::Opened { name, opened } => {
+                let waiting = self.state.opening.remove(&name).unwrap_or_default();
+                match opened {
+                    Ok(history) => {
+                        println!("Channel {name} started");
+                        let channel = channel::ChannelActorHandle::new(
+                            name.clone(),
+                            self.state.topics.clone(),
+                            self.state.shards,
+                            history,
+                        );
+                        self.state.channels.insert(
+                            name.clone(),
+                            ChannelEntry {
+                                channel,
+                                attendies: 0,
+                            },
+                        );
+                        for respond_to in waiting {
+                            self.seat(&name, respond_to);
+                        }
+                    }
+                    Err(err) => {
+                        println!("Failed to start channel {name}: {err}");
+                        // The others see the channel unavailable
+                        if let Some(respond_to) = waiting.into_iter().next() {
+                            let _ = respond_to.send(Err(err));
+                        }
+                    }
+                }
+            }
+            ActorMessage::Leave(name) => self.leave(&name),
+            ActorMessage::Publish {
+                name,
+                msg,
+                respond_to,
+            } => {
+                if let Some(entry) = self.state.channels.get(&name) {
+                    // Stamped right away, the journal writes it off the registry
+                    let published = entry.channel.publish(msg);
+                    tokio::spawn(async move {
+                        // Nobody waits for it without a journal
+                        if let Err(Err(err)) = respond_to.send(published.await) {
+                            println!("Dropping message for channel {name}: {err}");
+                        }
+                    });
+                } else {
+                    // Nobody holds a seat, but pattern subscribers can still match it
+                    let matched = self
+                        .state
+                        .topics
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .matches(&name);
+                    let msg = Broadcast::new(&name, None, msg);
+                    for (id, conn) in matched {
+                        if !conn.wants(&msg) {
+                            continue;
+                        }
+                        if let Err(err) = conn.send_message(session::Message::Match(msg.clone())) {
+                            println!("Dropping message for {id}: {err}");
+                        }
+                    }
+                    let _ = respond_to.send(Ok(()));
+                }
+            }
+            ActorMessage::Presence { name, respond_to } => {
+                let members = self
+                    .state
+                    .channels
+                    .get(&name)
+                    .map(|entry| entry.channel.presence())
+                    .unwrap_or_default();
+                let _ = respond_to.send(members);
+            }
+            ActorMessage::Stop => {
+                for (name, entry) in self.state.channels.drain() {
+                    entry.channel.stop();
+                    println!("Channel {name} stopped");
+                }
+                self.receiver.close();
+            }
+        }
+    }
+    /// Hand out a seat in the running channel
+    fn seat(
+        &mut self,
+        name: &str,
+        respond_to: oneshot::Sender<Result<channel::ChannelActorHandle, Error>>,
+    ) {
+        let Some(entry) = self.state.channels.get_mut(name) else {
+            return;
+        };
+        entry.attendies += 1;
+        if respond_to.send(Ok(entry.channel.clone())).is_err() {
+            // The caller went away before getting its seat, hand it back
+            self.leave(name);
+        }
+    }
+    fn leave(&mut self, name: &str) {
+        let Some(entry) = self.state.channels.get_mut(name) else {
+            return;
+        };
+        entry.attendies = entry.attendies.saturating_sub(1);
+        if entry.attendies == 0 {
+            entry.channel.stop();
+            self.state.channels.remove(name);
+            println!("Channel {name} stopped");
+        }
+    }
+}
+
+async fn run(mut actor: RegistryActor) {
+    while let Some(msg) = actor.receiver.recv().await {
+        actor.handle_message(msg);
+    }
+}
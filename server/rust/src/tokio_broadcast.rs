@@ -0,0 +1,352 @@
+//! The baseline without actors: every channel is a [tokio::sync::broadcast] sender and
+//! every session holds a receiver for each channel it joined.
+//!
+//! A publishing session sends to the channel itself and every receiver reads the
+//! broadcast off the same ring buffer, there are no balancers, channel tasks or
+//! connection mailboxes in between. A receiver that falls more than [Config::capacity]
+//! broadcasts behind skips the ones it missed, see [session::Message::Lagged]. A joining
+//! session takes its replay and its receiver under the same lock broadcasts are sent
+//! under, so the receiver starts right after the last broadcast replayed. A channel's
+//! journal is opened and written outside that lock, a broadcast is sent under it again
+//! once it was written.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{StreamExt, StreamMap};
+
+use crate::config;
+use crate::error::Error;
+use crate::history::{self, Appended, History, Replay, Replayed};
+use crate::presence::{Member, Members};
+use crate::protocol::{Broadcast, Payload, Publication};
+use crate::server::session::{self, ConnectionId};
+use crate::server::{Backend, Subscriber};
+use crate::topic::{self, TopicIndex};
+
+/// The `[broadcast]` section of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How many broadcasts a channel keeps around for its slowest receiver
+    pub capacity: usize,
+}
+
+impl Config {
+    /// Overrides the file with `BROADCAST_CAPACITY`
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        if let Some(capacity) = config::env("BROADCAST_CAPACITY")? {
+            self.capacity = capacity;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { capacity: 500 }
+    }
+}
+
+/// A channel somebody is in
+struct Channel {
+    sender: broadcast::Sender<Arc<Broadcast>>,
+    members: Members,
+    history: History,
+}
+
+#[derive(Default)]
+struct State {
+    /// Every channel somebody is in, dropped with its last receiver
+    channels: HashMap<String, Channel>,
+    topics: TopicIndex<ConnectionId, Subscriber>,
+}
+
+#[derive(Clone)]
+pub struct TokioBroadcast {
+    capacity: usize,
+    history: history::Config,
+    state: Arc<Mutex<State>>,
+    /// Held while a channel is opened outside the lock, so none is opened twice
+    opening: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// What this backend keeps for every connection
+pub struct Attendee {
+    subscriber: Subscriber,
+    /// A receiver for every channel we are in
+    channels: StreamMap<String, BroadcastStream<Arc<Broadcast>>>,
+}
+
+impl TokioBroadcast {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Subscribe to the channel and take the replay, starting the channel with the
+    /// history `opened` for it. [None] when it isn't started and nothing was opened.
+    fn seat(
+        &self,
+        subscriber: &Subscriber,
+        channel: &str,
+        replay: Option<Replay>,
+        opened: Option<History>,
+    ) -> Option<(broadcast::Receiver<Arc<Broadcast>>, Replayed)> {
+        let mut state = self.state();
+        let joined = match (state.channels.entry(channel.to_string()), opened) {
+            (Entry::Occupied(entry), _) => entry.into_mut(),
+            (Entry::Vacant(entry), Some(history)) => entry.insert(Channel {
+                sender: broadcast::channel(self.capacity).0,
+                members: Members::new(channel),
+                history,
+            }),
+            (Entry::Vacant(_), None) => return None,
+        };
+        let receiver = joined.sender.subscribe();
+        let replayed = Replayed {
+            epoch: joined.history.epoch(),
+            seq: joined.history.seq(),
+            broadcasts: replay.map_or_else(Vec::new, |replay| joined.history.replay(replay)),
+        };
+        if let Some(identity) = subscriber.identity() {
+            if let Some(notice) = joined.members.join(subscriber.get_id(), identity) {
+                let _ = joined.sender.send(notice);
+            }
+        }
+
+        Some((receiver, replayed))
+    }
+}
+
+impl State {
+    /// Send whatever the channel's history releases to its receivers and the matching
+    /// pattern subscribers. A channel stopped in the meantime reads it back from its
+    /// journal.
+    fn release(&mut self, channel: &str, appended: Appended) -> Result<(), Error> {
+        if let Some(joined) = self.channels.get_mut(channel) {
+            for msg in joined.history.release(&appended) {
+                // Receivers only go away under the lock, so there is at least one
+                let _ = joined.sender.send(msg.clone());
+                match_patterns(&self.topics, &msg);
+            }
+        }
+
+        appended.result
+    }
+}
+
+/// Hand the broadcast to every connection subscribed to a pattern it matches
+fn match_patterns(topics: &TopicIndex<ConnectionId, Subscriber>, msg: &Arc<Broadcast>) {
+    for (id, conn) in topics.matches(msg.channel()) {
+        if !conn.wants(msg) {
+            continue;
+        }
+        if let Err(err) = conn.send_message(session::Message::Match(msg.clone())) {
+            println!("Dropping message for {id}: {err}");
+        }
+    }
+}
+
+impl Backend for TokioBroadcast {
+    type Attendee = Attendee;
+
+    async fn start(config: &config::Config) -> Self {
+        Self {
+            capacity: config.broadcast.capacity,
+            history: config.history.clone(),
+            state: Arc::default(),
+            opening: Arc::default(),
+        }
+    }
+
+    async fn connect(&self, subscriber: Subscriber) -> Result<Self::Attendee, Error> {
+        Ok(Attendee {
+            subscriber,
+            channels: StreamMap::new(),
+        })
+    }
+
+    async fn join(
+        &self,
+        attendee: &mut Self::Attendee,
+        channel: &str,
+        replay: Option<Replay>,
+    ) -> Result<Replayed, Error> {
+        let subscriber = &attendee.subscriber;
+        if topic::is_pattern(channel) {
+            self.state()
+                .topics
+                .insert(channel, subscriber.get_id(), subscriber.clone());
+            return Ok(Replayed::default());
+        }
+
+        // A channel that isn't started yet reads its journal outside the lock, looking
+        // again once it is our turn to open it
+        let mut opening = None;
+        let mut opened = None;
+        let (receiver, replayed) = loop {
+            if let Some(seated) = self.seat(subscriber, channel, replay, opened.take()) {
+                break seated;
+            }
+            match opening {
+                None => opening = Some(self.opening.lock().await),
+                Some(_) => opened = Some(History::open(&self.history, channel).await?),
+            }
+        };
+        drop(opening);
+        attendee
+            .channels
+            .insert(channel.to_string(), BroadcastStream::new(receiver));
+
+        Ok(replayed)
+    }
+
+    fn leave(&self, attendee: &mut Self::Attendee, channel: &str) -> Result<(), Error> {
+        if topic::is_pattern(channel) {
+            self.state()
+                .topics
+                .remove(channel, &attendee.subscriber.get_id());
+            return Ok(());
+        }
+
+        // Dropping the receiver is all it takes to leave
+        attendee.channels.remove(channel);
+        let mut state = self.state();
+        let Some(joined) = state.channels.get_mut(channel) else {
+            return Ok(());
+        };
+        if joined.sender.receiver_count() == 0 {
+            state.channels.remove(channel);
+            println!("Channel {channel} stopped");
+        } else if let Some(identity) = attendee.subscriber.identity() {
+            if let Some(notice) = joined.members.leave(attendee.subscriber.get_id(), identity) {
+                let _ = joined.sender.send(notice);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn presence(
+        &self,
+        _attendee: &mut Self::Attendee,
+        channel: &str,
+    ) -> Result<Vec<Member>, Error> {
+        Ok(self
+            .state()
+            .channels
+            .get(channel)
+            .map(|joined| joined.members.list())
+            .unwrap_or_default())
+    }
+
+    async fn publish(
+        &self,
+        attendee: &mut Self::Attendee,
+        channel: &str,
+        msg: Payload,
+    ) -> Result<(), Error> {
+        let msg = Publication::new(attendee.subscriber.get_id(), msg);
+        let appending = {
+            let mut state = self.state();
+            // Stamped under the lock, so the sequence numbers go out in order
+            let Some(joined) = state.channels.get_mut(channel) else {
+                match_patterns(&state.topics, &Broadcast::new(channel, None, msg));
+                return Ok(());
+            };
+            match joined.history.append(channel, msg).try_written() {
+                Ok(appended) => return state.release(channel, appended),
+                Err(appending) => appending,
+            }
+        };
+
+        // The journal writes it outside the lock
+        let appended = appending.written().await;
+        self.state().release(channel, appended)
+    }
+
+    async fn deliver(&self, attendee: &mut Self::Attendee) -> session::Message {
+        loop {
+            match attendee.channels.next().await {
+                // Everybody in the channel reads the same buffer, so our own messages
+                // can only be skipped here
+                Some((_, Ok(msg))) if !attendee.subscriber.wants(&msg) => {}
+                Some((_, Ok(msg))) => return session::Message::Out(msg),
+                Some((channel, Err(BroadcastStreamRecvError::Lagged(missed)))) => {
+                    return session::Message::Lagged { channel, missed };
+                }
+                // Not in any channel, the next join starts a new wait
+                None => return std::future::pending().await,
+            }
+        }
+    }
+
+    fn disconnect(&self, _attendee: Self::Attendee) {}
+
+    async fn stop(&self) {
+        let mut state = self.state();
+        // Receivers see the channel end once its sender is dropped
+        state.channels.clear();
+        state.topics = TopicIndex::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn backend(capacity: usize) -> TokioBroadcast {
+        let mut config = config::Config::default();
+        config.broadcast.capacity = capacity;
+        TokioBroadcast::start(&config).await
+    }
+
+    async fn attendee(backend: &TokioBroadcast, id: ConnectionId) -> Attendee {
+        let (subscriber, _mailbox) = Subscriber::probe(id, 1);
+        backend.connect(subscriber).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_receivers_skip_what_they_missed() {
+        let backend = backend(2).await;
+        let mut alice = attendee(&backend, 1).await;
+        backend.join(&mut alice, "room", None).await.unwrap();
+
+        for msg in ["a", "b", "c", "d"] {
+            backend
+                .publish(&mut alice, "room", msg.into())
+                .await
+                .unwrap();
+        }
+
+        assert!(matches!(
+            backend.deliver(&mut alice).await,
+            session::Message::Lagged { missed: 2, .. }
+        ));
+        assert!(matches!(
+            backend.deliver(&mut alice).await,
+            session::Message::Out(msg) if msg.data() == "c".into()
+        ));
+    }
+
+    #[tokio::test]
+    async fn channel_stops_with_its_last_receiver() {
+        let backend = backend(2).await;
+        let mut alice = attendee(&backend, 1).await;
+        let mut bob = attendee(&backend, 2).await;
+        backend.join(&mut alice, "room", None).await.unwrap();
+        backend.join(&mut bob, "room", None).await.unwrap();
+
+        backend.leave(&mut alice, "room").unwrap();
+        assert!(backend.state().channels.contains_key("room"));
+
+        backend.leave(&mut bob, "room").unwrap();
+        assert!(backend.state().channels.is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+//! Wildcard topic subscriptions, shared by every backend.
+//!
+//! Channel names are hierarchical, with tokens separated by `.` (e.g. `sports.nfl.scores`).
+//! A pattern may use `*` to match exactly one token and, as its last token, `>` to match
+//! one or more tokens, so `sports.*` matches `sports.nfl` and `sports.>` matches both
+//! `sports.nfl` and `sports.nfl.scores`.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+const SINGLE: &str = "*";
+const TAIL: &str = ">";
+
+/// Returns true when the name contains a wildcard and has to be subscribed to as a pattern
+pub fn is_pattern(name: &str) -> bool {
+    name.split('.').any(|token| token == SINGLE || token == TAIL)
+}
+
+/// Returns true when every token is non-empty and `>` only appears as the last token
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    let tokens: Vec<&str> = pattern.split('.').collect();
+    tokens.iter().all(|token| !token.is_empty())
+        && !tokens[..tokens.len() - 1].contains(&TAIL)
+}
+
+struct Node<K, V> {
+    children: HashMap<String, Node<K, V>>,
+    subscribers: HashMap<K, V>,
+}
+
+impl<K, V> Node<K, V> {
+    fn new() -> Self {
+        Self {
+            children: HashMap::new(),
+            subscribers: HashMap::new(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.children.is_empty() && self.subscribers.is_empty()
+    }
+}
+
+/// A trie of subscription patterns, one token per level
+pub struct TopicIndex<K, V> {
+    root: Node<K, V>,
+}
+
+impl<K, V> Default for TopicIndex<K, V> {
+    fn default() -> Self {
+        Self { root: Node::new() }
+    }
+}
+
+impl<K, V> TopicIndex<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe `key` to `pattern`, replacing the value if it was already subscribed
+    pub fn insert(&mut self, pattern: &str, key: K, value: V) {
+        let mut node = &mut self.root;
+        for token in pattern.split('.') {
+            node = node
+                .children
+                .entry(token.to_string())
+                .or_insert_with(Node::new);
+        }
+        node.subscribers.insert(key, value);
+    }
+
+    /// Unsubscribe `key` from `pattern`, pruning the branch when nothing is left on it
+    pub fn remove(&mut self, pattern: &str, key: &K) {
+        let tokens: Vec<&str> = pattern.split('.').collect();
+        Self::remove_from(&mut self.root, &tokens, key);
+    }
+
+    fn remove_from(node: &mut Node<K, V>, tokens: &[&str], key: &K) {
+        let Some((token, rest)) = tokens.split_first() else {
+            node.subscribers.remove(key);
+            return;
+        };
+        if let Some(child) = node.children.get_mut(*token) {
+            Self::remove_from(child, rest, key);
+            if child.is_empty() {
+                node.children.remove(*token);
+            }
+        }
+    }
+
+    /// Every subscriber with at least one pattern matching `topic`, each one only once
+    pub fn matches(&self, topic: &str) -> HashMap<K, V> {
+        let tokens: Vec<&str> = topic.split('.').collect();
+        let mut found = HashMap::new();
+        Self::collect(&self.root, &tokens, &mut found);
+
+        found
+    }
+
+    fn collect(node: &Node<K, V>, tokens: &[&str], found: &mut HashMap<K, V>) {
+        let Some((token, rest)) = tokens.split_first() else {
+            for (key, value) in &node.subscribers {
+                found.insert(key.clone(), value.clone());
+            }
+            return;
+        };
+        if let Some(tail) = node.children.get(TAIL) {
+            for (key, value) in &tail.subscribers {
+                found.insert(key.clone(), value.clone());
+            }
+        }
+        if let Some(child) = node.children.get(*token) {
+            Self::collect(child, rest, found);
+        }
+        if let Some(child) = node.children.get(SINGLE) {
+            Self::collect(child, rest, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matched(index: &TopicIndex<u32, ()>, topic: &str) -> Vec<u32> {
+        let mut keys: Vec<u32> = index.matches(topic).into_keys().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn single_token_wildcard() {
+        let mut index = TopicIndex::new();
+        index.insert("sports.*", 1, ());
+
+        assert_eq!(matched(&index, "sports.nfl"), vec![1]);
+        assert_eq!(matched(&index, "sports"), Vec::<u32>::new());
+        assert_eq!(matched(&index, "sports.nfl.scores"), Vec::<u32>::new());
+        assert_eq!(matched(&index, "news.nfl"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn tail_wildcard() {
+        let mut index = TopicIndex::new();
+        index.insert("sports.>", 1, ());
+
+        assert_eq!(matched(&index, "sports.nfl"), vec![1]);
+        assert_eq!(matched(&index, "sports.nfl.scores"), vec![1]);
+        assert_eq!(matched(&index, "sports"), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn overlapping_patterns_from_different_subscribers() {
+        let mut index = TopicIndex::new();
+        index.insert("sports.*", 1, ());
+        index.insert("sports.>", 2, ());
+        index.insert("*.nfl", 3, ());
+        index.insert("sports.nfl", 4, ());
+        index.insert("sports.nba", 5, ());
+
+        assert_eq!(matched(&index, "sports.nfl"), vec![1, 2, 3, 4]);
+        assert_eq!(matched(&index, "sports.nfl.scores"), vec![2]);
+    }
+
+    #[test]
+    fn subscriber_matching_several_patterns_is_found_once() {
+        let mut index = TopicIndex::new();
+        index.insert("sports.*", 1, ());
+        index.insert("sports.>", 1, ());
+        index.insert("*.nfl", 1, ());
+        index.insert("sports.nfl", 1, ());
+
+        assert_eq!(index.matches("sports.nfl").len(), 1);
+    }
+
+    #[test]
+    fn remove_only_drops_that_pattern() {
+        let mut index = TopicIndex::new();
+        index.insert("sports.*", 1, ());
+        index.insert("sports.>", 1, ());
+        index.insert("sports.*", 2, ());
+
+        index.remove("sports.*", &1);
+        assert_eq!(matched(&index, "sports.nfl"), vec![1, 2]);
+        assert_eq!(matched(&index, "sports.nfl.scores"), vec![1]);
+
+        index.remove("sports.>", &1);
+        index.remove("sports.*", &2);
+        assert!(index.root.is_empty());
+    }
+
+    #[test]
+    fn pattern_validation() {
+        assert!(is_pattern("sports.*"));
+        assert!(is_pattern("sports.>"));
+        assert!(!is_pattern("sports.nfl"));
+
+        assert!(is_valid_pattern("sports.*.scores"));
+        assert!(is_valid_pattern("sports.>"));
+        assert!(!is_valid_pattern("sports.>.scores"));
+        assert!(!is_valid_pattern("sports..nfl"));
+    }
+}